@@ -0,0 +1,134 @@
+//! Who may link a GitHub account, configured with any of
+//!
+//! - `ALLOWED_ORGS`: org logins, membership of any of them is enough
+//! - `ALLOWED_TEAMS`: `org/team-slug`
+//! - `ALLOWED_USERS`: GitHub logins
+//!
+//! all comma separated. With none of them set everybody is allowed. Checking
+//! memberships needs the `read:org` scope (or the members permission of a
+//! GitHub App).
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    credential::GitHubIdentity, error::AppError, github_error, logout::revoke_grant,
+    try_fetch_identity,
+};
+
+static ALLOWED_ORGS: Lazy<Vec<String>> = Lazy::new(|| env_list("ALLOWED_ORGS"));
+static ALLOWED_TEAMS: Lazy<Vec<(String, String)>> = Lazy::new(|| {
+    env_list("ALLOWED_TEAMS")
+        .into_iter()
+        .map(|x| match x.split_once('/') {
+            Some((org, team)) => (org.to_string(), team.to_string()),
+            None => panic!("ALLOWED_TEAMS entry {x} is not org/team-slug"),
+        })
+        .collect()
+});
+static ALLOWED_USERS: Lazy<Vec<String>> = Lazy::new(|| env_list("ALLOWED_USERS"));
+
+#[derive(Deserialize, Debug)]
+struct Membership {
+    state: String,
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+pub fn init() {
+    if enabled() {
+        info!(
+            "Logins are limited to orgs {:?}, teams {:?} and users {:?}",
+            *ALLOWED_ORGS, *ALLOWED_TEAMS, *ALLOWED_USERS
+        );
+    }
+}
+
+fn enabled() -> bool {
+    !ALLOWED_ORGS.is_empty() || !ALLOWED_TEAMS.is_empty() || !ALLOWED_USERS.is_empty()
+}
+
+/// Whether the owner of `access_token` may log in, `NotAllowed` if not.
+pub async fn check(access_token: &str, identity: Option<&GitHubIdentity>) -> Result<(), AppError> {
+    if !enabled() {
+        return Ok(());
+    }
+
+    let login = match identity {
+        Some(x) => x.login.clone(),
+        None => {
+            try_fetch_identity(access_token)
+                .await
+                .map_err(|e| github_error(&e))?
+                .login
+        }
+    };
+
+    if ALLOWED_USERS.iter().any(|x| x.eq_ignore_ascii_case(&login)) {
+        return Ok(());
+    }
+
+    for org in ALLOWED_ORGS.iter() {
+        let url = format!("https://api.github.com/user/memberships/orgs/{org}");
+        if is_active_member(access_token, &url).await? {
+            return Ok(());
+        }
+    }
+
+    for (org, team) in ALLOWED_TEAMS.iter() {
+        let url = format!("https://api.github.com/orgs/{org}/teams/{team}/memberships/{login}");
+        if is_active_member(access_token, &url).await? {
+            return Ok(());
+        }
+    }
+
+    Err(AppError::NotAllowed(format!(
+        "GitHub user {login} is not on the allowlist"
+    )))
+}
+
+/// Like [`check`], but also hands a rejected token back to GitHub, since we
+/// are not going to keep it.
+pub async fn admit(access_token: &str, identity: Option<&GitHubIdentity>) -> Result<(), AppError> {
+    let res = check(access_token, identity).await;
+
+    if let Err(AppError::NotAllowed(_)) = res {
+        revoke_grant(access_token).await;
+    }
+
+    res
+}
+
+async fn is_active_member(access_token: &str, url: &str) -> Result<bool, AppError> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .get(url)
+        .bearer_auth(access_token)
+        .header("accept", "application/vnd.github+json")
+        .header("user-agent", "minzhengbu")
+        .send()
+        .await
+        .map_err(|e| github_error(&e))?;
+
+    // GitHub answers 404 to non-members, so nobody learns about private teams.
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    let membership: Membership = resp
+        .error_for_status()
+        .map_err(|e| github_error(&e))?
+        .json()
+        .await
+        .map_err(|e| github_error(&e))?;
+
+    Ok(membership.state == "active")
+}
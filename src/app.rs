@@ -0,0 +1,286 @@
+//! Installation access tokens of the GitHub App, for what the bot does as
+//! itself rather than on behalf of a user.
+//!
+//! Needs `GITHUB_APP_ID` and the PEM private key of the app in
+//! `GITHUB_APP_PRIVATE_KEY_FILE`. `GITHUB_APP_INSTALLATION_ID` is used for
+//! requests that don't name an installation.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{extract::Query, http::HeaderMap, response::IntoResponse, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::{
+    auth::{Scope, ServiceAuth},
+    env_or, error,
+    error::AppError,
+    github_error, now,
+};
+
+static APP_ID: Lazy<Option<String>> = Lazy::new(|| std::env::var("GITHUB_APP_ID").ok());
+static PRIVATE_KEY: Lazy<Option<PKey<Private>>> = Lazy::new(|| {
+    let path = std::env::var("GITHUB_APP_PRIVATE_KEY_FILE").ok()?;
+    let pem = std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+
+    Some(PKey::private_key_from_pem(&pem).unwrap_or_else(|e| panic!("Failed to parse {path}: {e}")))
+});
+static INSTALLATION_ID: Lazy<Option<u64>> = Lazy::new(|| {
+    std::env::var("GITHUB_APP_INSTALLATION_ID").ok().map(|x| {
+        x.parse()
+            .expect("GITHUB_APP_INSTALLATION_ID must be a number")
+    })
+});
+
+/// Cached tokens are handed out while they are valid for at least this many seconds.
+static APP_TOKEN_MIN_TTL: Lazy<i64> = Lazy::new(|| env_or("APP_TOKEN_MIN_TTL", 300));
+
+/// Cached token per installation, repositories and permissions. The lock also
+/// keeps concurrent requests for the same token from each minting one.
+static TOKENS: Lazy<DashMap<String, Arc<Mutex<Option<InstallationToken>>>>> =
+    Lazy::new(DashMap::new);
+
+pub fn enabled() -> bool {
+    match (&*APP_ID, &*PRIVATE_KEY) {
+        (Some(_), Some(_)) => true,
+        (None, None) => false,
+        _ => panic!("GITHUB_APP_ID and GITHUB_APP_PRIVATE_KEY_FILE must be set together"),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InstallationTokenArgs {
+    installation_id: Option<u64>,
+    /// Comma separated repository names, all repositories of the installation if absent.
+    repositories: Option<String>,
+    /// Comma separated `name:level`, e.g. `contents:write,pull_requests:read`.
+    permissions: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AccessTokenResponse {
+    token: String,
+    expires_at: String,
+    permissions: Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InstallationToken {
+    token: String,
+    expires_at: i64,
+    permissions: Value,
+}
+
+pub async fn installation_token(
+    auth: ServiceAuth,
+    Query(payload): Query<InstallationTokenArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::AppToken)?;
+
+    let installation_id = payload
+        .installation_id
+        .or(*INSTALLATION_ID)
+        .ok_or_else(|| AppError::BadRequest("Missing installation_id".into()))?;
+    let repositories = list(payload.repositories.as_deref());
+    let permissions = list(payload.permissions.as_deref())
+        .into_iter()
+        .map(|x| match x.split_once(':') {
+            Some((name, level)) => Ok((name.to_string(), level.to_string())),
+            None => Err(AppError::BadRequest(format!(
+                "Permission {x} is not name:level"
+            ))),
+        })
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+    let key = format!("{installation_id}|{repositories:?}|{permissions:?}");
+    let slot = TOKENS.entry(key).or_default().clone();
+    let mut cached = slot.lock().await;
+
+    let token = match &*cached {
+        Some(x) if x.expires_at - now() > *APP_TOKEN_MIN_TTL => x.clone(),
+        _ => {
+            evict_expired();
+            let token = mint(installation_id, &repositories, &permissions).await?;
+            *cached = Some(token.clone());
+            token
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((headers, Json(token)))
+}
+
+/// Forget expired tokens, so one-off sets of repositories and permissions
+/// don't pile up.
+fn evict_expired() {
+    let now = now();
+
+    TOKENS.retain(|_, slot| match slot.try_lock() {
+        Ok(x) => x.as_ref().is_some_and(|x| x.expires_at > now),
+        // Being minted or handed out right now.
+        Err(_) => true,
+    });
+}
+
+async fn mint(
+    installation_id: u64,
+    repositories: &[String],
+    permissions: &BTreeMap<String, String>,
+) -> Result<InstallationToken, AppError> {
+    let mut body = json!({});
+    if !repositories.is_empty() {
+        body["repositories"] = json!(repositories);
+    }
+    if !permissions.is_empty() {
+        body["permissions"] = json!(permissions);
+    }
+
+    let client = reqwest::Client::new();
+    let resp: AccessTokenResponse = client
+        .post(format!(
+            "https://api.github.com/app/installations/{installation_id}/access_tokens"
+        ))
+        .bearer_auth(jwt()?)
+        .header("accept", "application/vnd.github+json")
+        .header("user-agent", "minzhengbu")
+        .json(&body)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| github_error(&e))?
+        .json()
+        .await
+        .map_err(|e| github_error(&e))?;
+
+    let expires_at = parse_timestamp(&resp.expires_at)
+        .ok_or_else(|| AppError::GitHub(format!("Malformed expires_at {}", resp.expires_at)))?;
+
+    Ok(InstallationToken {
+        token: resp.token,
+        expires_at,
+        permissions: resp.permissions,
+    })
+}
+
+/// JWT that authenticates as the app itself, valid for 9 minutes.
+fn jwt() -> Result<String, AppError> {
+    let (Some(app_id), Some(key)) = (&*APP_ID, &*PRIVATE_KEY) else {
+        return Err(AppError::Internal("GitHub App is not configured".into()));
+    };
+
+    let now = now();
+    // Backdated a minute against clock drift; GitHub takes at most 10 minutes in total.
+    let claims = json!({ "iat": now - 60, "exp": now + 540, "iss": app_id });
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+
+    let mut signer = Signer::new(MessageDigest::sha256(), key).map_err(|e| error(&e))?;
+    signer.update(message.as_bytes()).map_err(|e| error(&e))?;
+    let signature = signer.sign_to_vec().map_err(|e| error(&e))?;
+
+    Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Unix time of a UTC timestamp like `2016-07-11T22:14:10Z`, which is all GitHub sends.
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let mut time = time.splitn(3, ':');
+    let (hour, minute) = (
+        time.next()?.parse::<i64>().ok()?,
+        time.next()?.parse::<i64>().ok()?,
+    );
+    // Drop fractional seconds.
+    let second = time.next()?.split('.').next()?.parse::<i64>().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn list(s: Option<&str>) -> Vec<String> {
+    let mut res: Vec<String> = s
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect();
+    res.sort();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Some(-1));
+        assert_eq!(parse_timestamp("2016-07-11T22:14:10Z"), Some(1468275250));
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(parse_timestamp("2024-02-29T12:00:00Z"), Some(1709208000));
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(951782400));
+        // 2100 is not a leap year.
+        assert_eq!(parse_timestamp("2100-03-01T00:00:00Z"), Some(4107542400));
+    }
+
+    #[test]
+    fn year_boundary() {
+        assert_eq!(parse_timestamp("2023-12-31T23:59:59Z"), Some(1704067199));
+        assert_eq!(parse_timestamp("2024-01-01T00:00:00Z"), Some(1704067200));
+    }
+
+    #[test]
+    fn fractional_seconds() {
+        assert_eq!(
+            parse_timestamp("2016-07-11T22:14:10.123Z"),
+            Some(1468275250)
+        );
+        assert_eq!(
+            parse_timestamp("2016-07-11T22:14:10.999999Z"),
+            Some(1468275250)
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse_timestamp("2016-07-11T22:14:10"), None);
+        assert_eq!(parse_timestamp("2016-07-11 22:14:10Z"), None);
+        assert_eq!(parse_timestamp("2016-13-11T22:14:10Z"), None);
+        assert_eq!(parse_timestamp("2016-07-00T22:14:10Z"), None);
+        assert_eq!(parse_timestamp("2016-07-11T22:14Z"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+}
@@ -0,0 +1,324 @@
+//! Authentication of internal callers (the bot, BuildIt, scripts).
+//!
+//! Requests are signed with HMAC-SHA256 over
+//!
+//! ```text
+//! METHOD\nPATH\nQUERY\nTIMESTAMP\nNONCE
+//! ```
+//!
+//! sent as hex in `x-minzhengbu-signature`, along with `x-minzhengbu-timestamp`
+//! (unix seconds), `x-minzhengbu-nonce` and `x-minzhengbu-client`.
+//!
+//! Clients are loaded from the JSON file at `CLIENTS_FILE`:
+//!
+//! ```json
+//! [{ "name": "buildit", "secrets": ["..."], "scopes": ["token:read"] }]
+//! ```
+//!
+//! `SECRETS` (comma separated, falling back to `SECRET`) is kept as the
+//! `default` client with the `admin` scope, which is what requests without
+//! `x-minzhengbu-client` are checked against. Any secret of a client is
+//! accepted, so secrets can be rotated by adding the new one, moving the
+//! client over, then dropping the old one.
+//!
+//! Callers that still send the plain `secret` header are accepted as well
+//! while `ALLOW_PLAIN_SECRET` is on, which it is by default for this release.
+//! Move the bot and other callers to signed requests first, then set
+//! `ALLOW_PLAIN_SECRET=0`; the next release will default to off.
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap},
+};
+use once_cell::sync::Lazy;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{env_or, error, error::AppError, now, store, store::TempKind, store_error};
+
+const DEFAULT_CLIENT: &str = "default";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Read stored tokens (`get_token`).
+    #[serde(rename = "token:read")]
+    TokenRead,
+    /// Force a refresh (`refresh_token`).
+    #[serde(rename = "token:refresh")]
+    TokenRefresh,
+    /// Store tokens on behalf of a user (device logins, `login_from_telegram`).
+    #[serde(rename = "token:write")]
+    TokenWrite,
+    /// Log users out (`logout`, `unlink`).
+    #[serde(rename = "token:revoke")]
+    TokenRevoke,
+    /// Find telegram users by GitHub account (`lookup`).
+    #[serde(rename = "user:lookup")]
+    UserLookup,
+    /// Mint installation tokens of the GitHub App (`installation_token`).
+    #[serde(rename = "app:token")]
+    AppToken,
+    /// Everything.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+#[derive(Deserialize, Debug)]
+struct Client {
+    name: String,
+    secrets: Vec<String>,
+    scopes: Vec<Scope>,
+}
+
+static CLIENTS: Lazy<Vec<Client>> = Lazy::new(|| {
+    let mut clients: Vec<Client> = match std::env::var("CLIENTS_FILE") {
+        Ok(path) => {
+            let s = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+            serde_json::from_str(&s).unwrap_or_else(|e| panic!("Failed to parse {path}: {e}"))
+        }
+        Err(_) => vec![],
+    };
+
+    if let Ok(secrets) = std::env::var("SECRETS").or_else(|_| std::env::var("SECRET")) {
+        clients.push(Client {
+            name: DEFAULT_CLIENT.to_string(),
+            secrets: secrets
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect(),
+            scopes: vec![Scope::Admin],
+        });
+    }
+
+    clients
+});
+
+/// How far the timestamp of a signed request may be from our clock, in seconds.
+static SIGNATURE_WINDOW: Lazy<i64> = Lazy::new(|| env_or("SIGNATURE_WINDOW", 300));
+
+/// Accept the plain `secret` header too, while callers move to signed requests.
+static ALLOW_PLAIN_SECRET: Lazy<bool> = Lazy::new(|| {
+    std::env::var("ALLOW_PLAIN_SECRET")
+        .ok()
+        .is_none_or(|x| x != "0")
+});
+
+pub fn init() {
+    assert!(
+        CLIENTS.iter().any(|x| !x.secrets.is_empty()),
+        "Neither CLIENTS_FILE nor SECRETS is set"
+    );
+
+    if *ALLOW_PLAIN_SECRET {
+        warn!("Unsigned requests with a plain secret are accepted, set ALLOW_PLAIN_SECRET=0 once all callers sign theirs");
+    }
+}
+
+/// Extractor that only succeeds for requests from an internal caller.
+pub struct ServiceAuth {
+    client: &'static Client,
+}
+
+impl ServiceAuth {
+    pub fn client(&self) -> &str {
+        &self.client.name
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        let scopes = &self.client.scopes;
+
+        if scopes.contains(&scope) || scopes.contains(&Scope::Admin) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Client {} lacks {scope:?}",
+                self.client()
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ServiceAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let client = if parts.headers.contains_key("x-minzhengbu-signature") {
+            verify_signature(parts).await?
+        } else if *ALLOW_PLAIN_SECRET {
+            plain_secret_check(&parts.headers)
+                .ok_or_else(|| AppError::Unauthorized("Secret does not match".into()))?
+        } else {
+            return Err(AppError::Unauthorized("Request is not signed".into()));
+        };
+
+        info!("{} {} {}", client.name, parts.method, parts.uri.path());
+
+        Ok(ServiceAuth { client })
+    }
+}
+
+async fn verify_signature(parts: &Parts) -> Result<&'static Client, AppError> {
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .ok_or_else(|| AppError::Unauthorized(format!("Missing {name}")))
+    };
+
+    let signature = header("x-minzhengbu-signature")?;
+    let timestamp = header("x-minzhengbu-timestamp")?;
+    let nonce = header("x-minzhengbu-nonce")?;
+    let name = parts
+        .headers
+        .get("x-minzhengbu-client")
+        .map(|x| x.to_str().unwrap_or_default())
+        .unwrap_or(DEFAULT_CLIENT);
+
+    let Some(client) = CLIENTS.iter().find(|x| x.name == name) else {
+        return Err(AppError::Unauthorized(format!("Unknown client {name}")));
+    };
+
+    let Ok(ts) = timestamp.parse::<i64>() else {
+        return Err(AppError::Unauthorized("Malformed timestamp".into()));
+    };
+
+    if (now() - ts).abs() > *SIGNATURE_WINDOW {
+        return Err(AppError::Unauthorized(
+            "Timestamp is outside of the signature window".into(),
+        ));
+    }
+
+    if !(8..=64).contains(&nonce.len()) || !nonce.bytes().all(|x| x.is_ascii_alphanumeric()) {
+        return Err(AppError::Unauthorized("Malformed nonce".into()));
+    }
+
+    let message = signed_message(
+        parts.method.as_str(),
+        parts.uri.path(),
+        parts.uri.query().unwrap_or_default(),
+        timestamp,
+        nonce,
+    );
+
+    if !matches_any(&client.secrets, &message, signature).map_err(|e| error(&e))? {
+        return Err(AppError::Unauthorized(format!(
+            "Signature of client {name} does not match"
+        )));
+    }
+
+    // Only claim the nonce once the signature is good, so nobody can burn nonces.
+    let ttl = 2 * *SIGNATURE_WINDOW as u64;
+    if !store()?
+        .claim_temp(TempKind::Nonce, &format!("{name}:{nonce}"), ttl)
+        .await
+        .map_err(|e| store_error(&e))?
+    {
+        return Err(AppError::ReplayedRequest(format!(
+            "Nonce {nonce} of client {name} has been used"
+        )));
+    }
+
+    Ok(client)
+}
+
+/// What a request signature is computed over, see the module docs.
+fn signed_message(method: &str, path: &str, query: &str, timestamp: &str, nonce: &str) -> String {
+    format!("{method}\n{path}\n{query}\n{timestamp}\n{nonce}")
+}
+
+/// Whether `signature` is that of `message` under any of `secrets`.
+fn matches_any(
+    secrets: &[String],
+    message: &str,
+    signature: &str,
+) -> Result<bool, openssl::error::ErrorStack> {
+    let mut matched = false;
+    for secret in secrets {
+        let expected = sign(secret, message)?;
+        // Keep going after a match so timing doesn't tell which secret was used.
+        matched |= expected.len() == signature.len()
+            && memcmp::eq(expected.as_bytes(), signature.as_bytes());
+    }
+
+    Ok(matched)
+}
+
+fn plain_secret_check(headers: &HeaderMap) -> Option<&'static Client> {
+    let secret = headers.get("secret")?.as_bytes();
+    let mut found = None;
+
+    for client in CLIENTS.iter() {
+        for x in &client.secrets {
+            if x.len() == secret.len() && memcmp::eq(x.as_bytes(), secret) {
+                found = found.or(Some(client));
+            }
+        }
+    }
+
+    found
+}
+
+/// Lowercase hex HMAC-SHA256 of `message`.
+pub fn sign(secret: &str, message: &str) -> Result<String, openssl::error::ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(message.as_bytes())?;
+
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNATURE: &str = "04b8bed2c708dc84a4689a0c5edf9f0126aa509255b13b18a72e8dbd6509846d";
+
+    fn message() -> String {
+        signed_message(
+            "GET",
+            "/get_token",
+            "id=42&bot=aosc_buildit_bot",
+            "1700000000",
+            "abcdefgh12345678",
+        )
+    }
+
+    #[test]
+    fn message_layout() {
+        assert_eq!(
+            message(),
+            "GET\n/get_token\nid=42&bot=aosc_buildit_bot\n1700000000\nabcdefgh12345678"
+        );
+        assert_eq!(
+            signed_message("GET", "/lookup", "", "1", "abcdefgh"),
+            "GET\n/lookup\n\n1\nabcdefgh"
+        );
+    }
+
+    #[test]
+    fn known_signature() {
+        assert_eq!(sign("s3cret", &message()).unwrap(), SIGNATURE);
+    }
+
+    #[test]
+    fn any_secret_matches() {
+        let secrets = ["old".to_string(), "s3cret".to_string()];
+        assert!(matches_any(&secrets, &message(), SIGNATURE).unwrap());
+        assert!(!matches_any(&secrets[..1], &message(), SIGNATURE).unwrap());
+        assert!(!matches_any(&secrets, &message(), &SIGNATURE.to_uppercase()).unwrap());
+        assert!(!matches_any(&secrets, &message(), &SIGNATURE[1..]).unwrap());
+
+        let other = signed_message("POST", "/get_token", "id=42", "1700000000", "abcdefgh");
+        assert!(!matches_any(&secrets, &other, SIGNATURE).unwrap());
+    }
+}
@@ -0,0 +1,100 @@
+//! Telegram bots served by this instance, from `TELEGRAM_BOTS` (comma
+//! separated usernames, `aosc_buildit_bot` by default). Requests may name one
+//! with `bot`, the first one is used otherwise.
+//!
+//! Credentials are kept per bot, so e.g. a staging bot can't overwrite the
+//! logins of the production one. Those of the first bot are stored under the
+//! bare telegram id, as they were before there could be more than one bot, so
+//! keep it first.
+//!
+//! Checking what Telegram signed on behalf of a user (the Login Widget, Mini
+//! Apps) needs the API token of the bot, from `TELEGRAM_BOT_TOKENS`: comma
+//! separated `username=token`.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+
+use crate::error::AppError;
+
+static BOTS: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("TELEGRAM_BOTS")
+        .unwrap_or_else(|_| "aosc_buildit_bot".to_string())
+        .split(',')
+        .map(|x| x.trim().trim_start_matches('@').to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+});
+static TOKENS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    std::env::var("TELEGRAM_BOT_TOKENS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| match x.split_once('=') {
+            Some((bot, token)) => (
+                resolve(Some(bot.trim()))
+                    .unwrap_or_else(|_| panic!("TELEGRAM_BOT_TOKENS names unknown bot {bot}"))
+                    .to_string(),
+                token.trim().to_string(),
+            ),
+            None => panic!("TELEGRAM_BOT_TOKENS entry is not username=token"),
+        })
+        .collect()
+});
+
+pub fn init() {
+    assert!(!BOTS.is_empty(), "TELEGRAM_BOTS is empty");
+
+    for bot in BOTS.iter() {
+        assert!(
+            bot.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'_'),
+            "{bot} is not a telegram bot username"
+        );
+    }
+
+    Lazy::force(&TOKENS);
+}
+
+fn default() -> &'static str {
+    &BOTS[0]
+}
+
+/// The bot named by a request, or the default one.
+pub fn resolve(bot: Option<&str>) -> Result<&'static str, AppError> {
+    let Some(bot) = bot else {
+        return Ok(default());
+    };
+
+    BOTS.iter()
+        .find(|x| x.eq_ignore_ascii_case(bot.trim_start_matches('@')))
+        .map(|x| x.as_str())
+        .ok_or_else(|| AppError::BadRequest(format!("Unknown bot {bot}")))
+}
+
+/// API token of `bot`, if configured.
+pub fn token(bot: &str) -> Option<&'static str> {
+    TOKENS.get(bot).map(|x| x.as_str())
+}
+
+/// Id the credential of `telegram_id` is stored under for `bot`.
+pub fn account(bot: &str, telegram_id: &str) -> String {
+    if bot == default() {
+        telegram_id.to_string()
+    } else {
+        format!("{bot}:{telegram_id}")
+    }
+}
+
+/// Inverse of [`account`], `None` if `account` belongs to another bot.
+pub fn telegram_id<'a>(bot: &str, account: &'a str) -> Option<&'a str> {
+    if bot == default() {
+        (!account.contains(':')).then_some(account)
+    } else {
+        account.strip_prefix(bot)?.strip_prefix(':')
+    }
+}
+
+/// Deep link that opens `bot` with `/start <start>`.
+pub fn start_link(bot: &str, start: &str) -> String {
+    format!("https://t.me/{bot}?start={start}")
+}
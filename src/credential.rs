@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{now, CallbackSecondLoginArgs};
+
+pub const CREDENTIAL_VERSION: u32 = 1;
+
+/// What is actually stored for a telegram id.
+///
+/// The token fields are flattened so the JSON stays a superset of
+/// `CallbackSecondLoginArgs`, which is what `get_token` used to return.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct StoredCredential {
+    pub version: u32,
+    #[serde(flatten)]
+    pub token: CallbackSecondLoginArgs,
+    pub issued_at: i64,
+    /// `None` for tokens that never expire.
+    pub access_expires_at: Option<i64>,
+    pub refresh_expires_at: Option<i64>,
+    /// GitHub account the token belongs to. Missing if GitHub could not be
+    /// asked at login; filled in on the next refresh.
+    #[serde(default)]
+    pub identity: Option<GitHubIdentity>,
+    /// GitHub rejected the refresh token, only a new login can fix this.
+    #[serde(default)]
+    pub needs_relogin: bool,
+}
+
+/// As returned by GitHub's `GET /user`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GitHubIdentity {
+    pub login: String,
+    pub id: u64,
+    pub name: Option<String>,
+    /// Primary email, from `GET /user/emails` if it is not public.
+    pub email: Option<String>,
+}
+
+impl StoredCredential {
+    /// Wrap a token that GitHub has just issued.
+    pub fn issue(token: CallbackSecondLoginArgs, identity: Option<GitHubIdentity>) -> Self {
+        let issued_at = now();
+
+        Self {
+            version: CREDENTIAL_VERSION,
+            issued_at,
+            access_expires_at: token.expires_in.map(|x| issued_at + x),
+            refresh_expires_at: token.refresh_token_expires_in.map(|x| issued_at + x),
+            identity,
+            needs_relogin: false,
+            token,
+        }
+    }
+
+    /// Legacy blobs carry no issue time, so assume the access token has just
+    /// expired: that gets it refreshed right away and the refresh records the
+    /// real times.
+    fn from_legacy(token: CallbackSecondLoginArgs) -> Self {
+        let issued_at = now() - token.expires_in.unwrap_or_default();
+
+        Self {
+            version: CREDENTIAL_VERSION,
+            issued_at,
+            access_expires_at: token.expires_in.map(|x| issued_at + x),
+            refresh_expires_at: token.refresh_token_expires_in.map(|x| issued_at + x),
+            identity: None,
+            needs_relogin: false,
+            token,
+        }
+    }
+
+    /// When the access token expires, if the background refresh should take
+    /// care of it.
+    pub fn refresh_at(&self) -> Option<i64> {
+        if self.needs_relogin || self.token.refresh_token.is_none() {
+            return None;
+        }
+
+        self.access_expires_at
+    }
+
+    /// Parse a stored blob, returning whether it was in the legacy format.
+    pub fn decode(s: &str) -> serde_json::Result<(Self, bool)> {
+        let value: Value = serde_json::from_str(s)?;
+
+        if value.get("version").is_some() {
+            Ok((serde_json::from_value(value)?, false))
+        } else {
+            let token = serde_json::from_value(value)?;
+            Ok((Self::from_legacy(token), true))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the service stored before credentials were versioned.
+    const LEGACY: &str = r#"{"access_token":"ghu_abc","expires_in":28800,"refresh_token":"ghr_def","refresh_token_expires_in":15897600,"scope":"","token_type":"bearer"}"#;
+
+    #[test]
+    fn decode_legacy() {
+        let (credential, legacy) = StoredCredential::decode(LEGACY).unwrap();
+
+        assert!(legacy);
+        assert_eq!(credential.version, CREDENTIAL_VERSION);
+        assert_eq!(credential.token.access_token, "ghu_abc");
+        assert_eq!(credential.token.refresh_token.as_deref(), Some("ghr_def"));
+        assert!(credential.access_expires_at.is_some_and(|x| x <= now()));
+        assert!(credential.refresh_expires_at.is_some_and(|x| x > now()));
+        assert!(credential.identity.is_none());
+        assert!(!credential.needs_relogin);
+    }
+
+    #[test]
+    fn decode_versioned() {
+        let (credential, _) = StoredCredential::decode(LEGACY).unwrap();
+        let s = serde_json::to_string(&credential).unwrap();
+        let (decoded, legacy) = StoredCredential::decode(&s).unwrap();
+
+        assert!(!legacy);
+        assert_eq!(decoded.token.access_token, credential.token.access_token);
+        assert_eq!(decoded.issued_at, credential.issued_at);
+        assert_eq!(decoded.access_expires_at, credential.access_expires_at);
+        assert_eq!(decoded.refresh_expires_at, credential.refresh_expires_at);
+
+        // Still readable as the token `get_token` used to return.
+        let token: CallbackSecondLoginArgs = serde_json::from_str(&s).unwrap();
+        assert_eq!(token.refresh_token.as_deref(), Some("ghr_def"));
+    }
+}
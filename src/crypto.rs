@@ -0,0 +1,249 @@
+use std::{collections::HashMap, io};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use once_cell::sync::Lazy;
+use openssl::{
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+
+/// Prefix of sealed values, followed by `<key id>:<base64(nonce | ciphertext | tag)>`.
+const SEALED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// AES-256-GCM keys by id, from `TOKEN_KEYS="id1:base64key,id2:base64key"`.
+///
+/// New values are sealed with `TOKEN_KEY_ID` (the first key by default); the
+/// other keys are only kept to open values sealed before a rotation.
+static KEYS: Lazy<HashMap<String, Vec<u8>>> = Lazy::new(|| {
+    let Ok(keys) = std::env::var("TOKEN_KEYS") else {
+        return HashMap::new();
+    };
+
+    keys.split(',')
+        .map(|x| {
+            let (id, key) = x
+                .trim()
+                .split_once(':')
+                .expect("TOKEN_KEYS entries must look like id:base64key");
+            let key = STANDARD
+                .decode(key)
+                .expect("TOKEN_KEYS keys must be base64 encoded");
+            assert_eq!(key.len(), 32, "TOKEN_KEYS keys must be 32 bytes long");
+
+            (id.to_string(), key)
+        })
+        .collect()
+});
+
+static ACTIVE_KEY_ID: Lazy<Option<String>> = Lazy::new(|| {
+    let id = std::env::var("TOKEN_KEY_ID").ok().or_else(|| {
+        std::env::var("TOKEN_KEYS")
+            .ok()
+            .and_then(|x| x.split(':').next().map(|x| x.trim().to_string()))
+    })?;
+
+    assert!(
+        KEYS.contains_key(&id),
+        "TOKEN_KEY_ID {id} is not in TOKEN_KEYS"
+    );
+
+    Some(id)
+});
+
+pub fn enabled() -> bool {
+    ACTIVE_KEY_ID.is_some()
+}
+
+/// Encrypt `plaintext` with the active key. `aad` binds the value to what it
+/// is stored for, so sealed values can't be swapped between users.
+///
+/// Without `TOKEN_KEYS` this is a no-op.
+pub fn seal(aad: &str, plaintext: &str) -> io::Result<String> {
+    seal_with(&KEYS, ACTIVE_KEY_ID.as_deref(), aad, plaintext)
+}
+
+/// Decrypt a value produced by [`seal`]. Values stored before encryption was
+/// enabled are returned as is.
+pub fn open(aad: &str, s: &str) -> io::Result<String> {
+    open_with(&KEYS, aad, s)
+}
+
+/// Whether `s` should be sealed again: it is plaintext while encryption is
+/// enabled, or was sealed with a key other than the active one.
+pub fn needs_reseal(s: &str) -> bool {
+    needs_reseal_with(ACTIVE_KEY_ID.as_deref(), s)
+}
+
+/// [`seal`] with `keys` and `active` instead of the environment.
+fn seal_with(
+    keys: &HashMap<String, Vec<u8>>,
+    active: Option<&str>,
+    aad: &str,
+    plaintext: &str,
+) -> io::Result<String> {
+    let Some(id) = active else {
+        return Ok(plaintext.to_string());
+    };
+    let key = keys
+        .get(id)
+        .ok_or_else(|| io::Error::other(format!("Unknown token key id: {id}")))?;
+
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(io::Error::other)?;
+
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        aad.as_bytes(),
+        plaintext.as_bytes(),
+        &mut tag,
+    )
+    .map_err(io::Error::other)?;
+
+    let mut buf = nonce.to_vec();
+    buf.extend(ciphertext);
+    buf.extend(tag);
+
+    Ok(format!("{SEALED_PREFIX}{id}:{}", STANDARD.encode(buf)))
+}
+
+/// [`open`] with `keys` instead of the environment.
+fn open_with(keys: &HashMap<String, Vec<u8>>, aad: &str, s: &str) -> io::Result<String> {
+    let Some(sealed) = s.strip_prefix(SEALED_PREFIX) else {
+        return Ok(s.to_string());
+    };
+
+    let (id, data) = sealed
+        .split_once(':')
+        .ok_or_else(|| io::Error::other("Malformed sealed value"))?;
+    let key = keys
+        .get(id)
+        .ok_or_else(|| io::Error::other(format!("Unknown token key id: {id}")))?;
+    let data = STANDARD.decode(data).map_err(io::Error::other)?;
+
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err(io::Error::other("Sealed value is too short"));
+    }
+
+    let (nonce, rest) = data.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    let plaintext = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad.as_bytes(),
+        ciphertext,
+        tag,
+    )
+    .map_err(io::Error::other)?;
+
+    String::from_utf8(plaintext).map_err(io::Error::other)
+}
+
+/// [`needs_reseal`] with `active` instead of the environment.
+fn needs_reseal_with(active: Option<&str>, s: &str) -> bool {
+    let Some(active) = active else {
+        return false;
+    };
+
+    s.strip_prefix(SEALED_PREFIX)
+        .and_then(|x| x.split_once(':'))
+        .map(|(id, _)| id != active)
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> HashMap<String, Vec<u8>> {
+        [("k1", [1; 32]), ("k2", [2; 32])]
+            .into_iter()
+            .map(|(id, key)| (id.to_string(), key.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealed = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+
+        assert!(sealed.starts_with("enc:v1:k1:"));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(open_with(&keys(), "cred:42", &sealed).unwrap(), "secret");
+
+        // Fresh nonce every time.
+        let again = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+        assert_ne!(sealed, again);
+    }
+
+    #[test]
+    fn other_aad() {
+        let sealed = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+
+        assert!(open_with(&keys(), "cred:43", &sealed).is_err());
+        assert!(open_with(&keys(), "pending:42", &sealed).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let sealed = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+        let (prefix, data) = sealed.rsplit_once(':').unwrap();
+        let data = STANDARD.decode(data).unwrap();
+
+        // Flip a bit of the ciphertext, then of the tag.
+        for i in [NONCE_LEN, data.len() - 1] {
+            let mut data = data.clone();
+            data[i] ^= 1;
+            let tampered = format!("{prefix}:{}", STANDARD.encode(&data));
+            assert!(open_with(&keys(), "cred:42", &tampered).is_err());
+        }
+
+        let truncated = format!("{prefix}:{}", STANDARD.encode(&data[..NONCE_LEN]));
+        assert!(open_with(&keys(), "cred:42", &truncated).is_err());
+
+        // Another key under the same id.
+        let sealed = seal_with(&keys(), Some("k2"), "cred:42", "secret").unwrap();
+        let swapped = sealed.replacen("enc:v1:k2:", "enc:v1:k1:", 1);
+        assert!(open_with(&keys(), "cred:42", &swapped).is_err());
+    }
+
+    #[test]
+    fn unknown_key_id() {
+        let sealed = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+        let mut keys = keys();
+        keys.remove("k1");
+
+        assert!(open_with(&keys, "cred:42", &sealed).is_err());
+        assert!(seal_with(&keys, Some("k1"), "cred:42", "secret").is_err());
+    }
+
+    #[test]
+    fn plaintext() {
+        assert_eq!(open_with(&keys(), "cred:42", "secret").unwrap(), "secret");
+        assert_eq!(
+            seal_with(&keys(), None, "cred:42", "secret").unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn reseal_after_rotation() {
+        let sealed = seal_with(&keys(), Some("k1"), "cred:42", "secret").unwrap();
+
+        assert!(!needs_reseal_with(Some("k1"), &sealed));
+        assert!(needs_reseal_with(Some("k2"), &sealed));
+        assert!(needs_reseal_with(Some("k1"), "secret"));
+        assert!(!needs_reseal_with(None, "secret"));
+        assert!(!needs_reseal_with(None, &sealed));
+
+        // The retired key still opens what it sealed.
+        let resealed = seal_with(&keys(), Some("k2"), "cred:42", "secret").unwrap();
+        assert_eq!(open_with(&keys(), "cred:42", &sealed).unwrap(), "secret");
+        assert!(!needs_reseal_with(Some("k2"), &resealed));
+    }
+}
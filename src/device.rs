@@ -0,0 +1,231 @@
+use axum::{extract::Query, http::HeaderMap, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allowlist,
+    auth::{Scope, ServiceAuth},
+    bot,
+    credential::StoredCredential,
+    crypto, error,
+    error::AppError,
+    fetch_identity, fetch_token, github_error, now, random_id, store,
+    store::{CredentialStore, TempKind},
+    store_credential, store_error, CallbackSecondLoginArgs, CLIENT_ID,
+};
+
+#[derive(Deserialize, Debug)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: i64,
+}
+
+/// The device code never leaves minzhengbu, clients only get a handle to it.
+#[derive(Deserialize, Serialize, Debug)]
+struct DeviceLogin {
+    device_code: String,
+    interval: i64,
+    next_poll_at: i64,
+    expires_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeviceStart {
+    handle: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DevicePollArgs {
+    handle: String,
+    telegram_id: Option<String>,
+    bot: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DevicePoll {
+    AuthorizationPending {
+        interval: i64,
+    },
+    SlowDown {
+        interval: i64,
+    },
+    Complete {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<CallbackSecondLoginArgs>,
+    },
+}
+
+pub async fn login_device() -> Result<impl IntoResponse, AppError> {
+    let store = store()?;
+
+    let client = reqwest::Client::new();
+    let resp: DeviceCodeResponse = client
+        .post("https://github.com/login/device/code")
+        .header("accept", "application/json")
+        .query(&[("client_id", CLIENT_ID.as_str())])
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| github_error(&e))?
+        .json()
+        .await
+        .map_err(|e| github_error(&e))?;
+
+    let handle = random_id();
+    let now = now();
+    let login = DeviceLogin {
+        device_code: resp.device_code,
+        interval: resp.interval,
+        next_poll_at: now,
+        expires_at: now + resp.expires_in as i64,
+    };
+
+    save(store, &handle, &login).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((
+        headers,
+        Json(DeviceStart {
+            handle,
+            user_code: resp.user_code,
+            verification_uri: resp.verification_uri,
+            expires_in: resp.expires_in,
+            interval: resp.interval,
+        }),
+    ))
+}
+
+/// Poll GitHub once for the device authorization behind `handle`.
+///
+/// With `telegram_id` (secret required) the token is stored for that user
+/// instead of being returned.
+pub async fn login_device_poll(
+    auth: Option<ServiceAuth>,
+    Query(payload): Query<DevicePollArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    let DevicePollArgs {
+        handle,
+        telegram_id,
+        bot,
+    } = payload;
+
+    if telegram_id.is_some() {
+        let Some(auth) = auth else {
+            return Err(AppError::Unauthorized(
+                "Storing a device login needs a service request".into(),
+            ));
+        };
+
+        auth.require(Scope::TokenWrite)?;
+    }
+
+    let bot = bot::resolve(bot.as_deref())?;
+    let store = store()?;
+
+    let mut login = load(store, &handle).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    // Polling faster than the interval would get the client code `slow_down` from GitHub.
+    if now() < login.next_poll_at {
+        let interval = login.interval;
+        return Ok((headers, Json(DevicePoll::AuthorizationPending { interval })));
+    }
+
+    let resp = fetch_token(&[
+        ("client_id", CLIENT_ID.as_str()),
+        ("device_code", &login.device_code),
+        ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+    ])
+    .await?;
+
+    let poll = match resp.error.as_deref() {
+        Some("authorization_pending") => DevicePoll::AuthorizationPending {
+            interval: login.interval,
+        },
+        Some("slow_down") => {
+            login.interval = resp.interval.unwrap_or(login.interval + 5);
+            DevicePoll::SlowDown {
+                interval: login.interval,
+            }
+        }
+        // Anything else ends the device login, one way or the other.
+        e => {
+            store
+                .delete_temp(TempKind::DeviceLogin, &handle)
+                .await
+                .map_err(|e| store_error(&e))?;
+
+            if e == Some("expired_token") {
+                return Err(AppError::Gone(format!("Device login {handle} has expired")));
+            }
+
+            let token = resp.into_token()?;
+            let identity = fetch_identity(&token.access_token).await;
+            allowlist::admit(&token.access_token, identity.as_ref()).await?;
+
+            let token = match telegram_id {
+                Some(telegram_id) => {
+                    let credential = StoredCredential::issue(token, identity);
+                    let id = bot::account(bot, &telegram_id);
+                    store_credential(store, &id, &credential).await?;
+                    None
+                }
+                None => Some(token),
+            };
+
+            return Ok((headers, Json(DevicePoll::Complete { token })));
+        }
+    };
+
+    login.next_poll_at = now() + login.interval;
+    save(store, &handle, &login).await?;
+
+    Ok((headers, Json(poll)))
+}
+
+async fn save(
+    store: &dyn CredentialStore,
+    handle: &str,
+    login: &DeviceLogin,
+) -> Result<(), AppError> {
+    let ttl = login.expires_at - now();
+    if ttl <= 0 {
+        return Err(AppError::Gone(format!("Device login {handle} has expired")));
+    }
+
+    // Together with the public client id, the device code is as good as the token.
+    let s = serde_json::to_string(login).map_err(|e| error(&e))?;
+    let s = crypto::seal(&device_aad(handle), &s).map_err(|e| error(&e))?;
+    store
+        .put_temp(TempKind::DeviceLogin, handle, &s, ttl as u64)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    Ok(())
+}
+
+async fn load(store: &dyn CredentialStore, handle: &str) -> Result<DeviceLogin, AppError> {
+    let s = store
+        .get_temp(TempKind::DeviceLogin, handle)
+        .await
+        .map_err(|e| store_error(&e))?
+        .ok_or_else(|| AppError::NotFound(format!("Unknown or expired device login {handle}")))?;
+    let s = crypto::open(&device_aad(handle), &s).map_err(|e| error(&e))?;
+
+    serde_json::from_str(&s).map_err(|e| error(&e))
+}
+
+fn device_aad(handle: &str) -> String {
+    format!("device:{handle}")
+}
@@ -0,0 +1,188 @@
+//! Errors returned to callers.
+//!
+//! Every error response has a JSON body `{"code", "message", "request_id"}`.
+//! `code` is one of the stable values below, which is what callers should
+//! switch on; `message` is for humans and may change. `request_id` is also
+//! logged with the details of the error. `github_rejected` errors also carry
+//! GitHub's own `{"error", "error_description", "error_uri"}` as `github`.
+//!
+//! | code                  | status | meaning                                       |
+//! |-----------------------|--------|-----------------------------------------------|
+//! | `bad_request`         | 400    | malformed or missing parameters               |
+//! | `unauthorized`        | 401    | service request is not signed or signed wrong |
+//! | `forbidden`           | 403    | authenticated, but not allowed to do this     |
+//! | `not_allowed`         | 403    | the GitHub user is not on the allowlist       |
+//! | `not_logged_in`       | 404    | the telegram user has no stored credential    |
+//! | `not_found`           | 404    | unknown or expired login, state or handle     |
+//! | `replayed_request`    | 409    | the nonce of a signed request was reused      |
+//! | `not_refreshable`     | 409    | the token never expires, nothing to refresh   |
+//! | `gone`                | 410    | the device login has expired                  |
+//! | `relogin_required`    | 410    | GitHub revoked the grant, log in again        |
+//! | `github_rejected`     | 400    | GitHub rejected the code or token             |
+//! | `github_unavailable`  | 502    | GitHub could not be reached or failed         |
+//! | `storage_unavailable` | 503    | the credential store could not be reached     |
+//! | `internal`            | 500    | anything else                                 |
+
+use std::fmt;
+
+use axum::{
+    http::{HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::log::{error, warn};
+
+use crate::random_id;
+
+/// The `String` of each variant is the detail that goes to the log. It is
+/// only shown to the caller for variants the caller can do something about.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotAllowed(String),
+    NotLoggedIn(String),
+    NotFound(String),
+    ReplayedRequest(String),
+    NotRefreshable(String),
+    Gone(String),
+    ReloginRequired(String),
+    /// GitHub answered the token endpoint with an error instead of a token.
+    OAuth(OAuthError),
+    GitHub(String),
+    StoreUnavailable(String),
+    Internal(String),
+}
+
+/// Error form of GitHub's token endpoint, which comes with status 200.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OAuthError {
+    pub error: String,
+    pub error_description: Option<String>,
+    pub error_uri: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    request_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    github: Option<&'a OAuthError>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) | AppError::NotAllowed(_) => StatusCode::FORBIDDEN,
+            AppError::NotLoggedIn(_) | AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::ReplayedRequest(_) | AppError::NotRefreshable(_) => StatusCode::CONFLICT,
+            AppError::Gone(_) | AppError::ReloginRequired(_) => StatusCode::GONE,
+            AppError::OAuth(_) => StatusCode::BAD_REQUEST,
+            AppError::GitHub(_) => StatusCode::BAD_GATEWAY,
+            AppError::StoreUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotAllowed(_) => "not_allowed",
+            AppError::NotLoggedIn(_) => "not_logged_in",
+            AppError::NotFound(_) => "not_found",
+            AppError::ReplayedRequest(_) => "replayed_request",
+            AppError::NotRefreshable(_) => "not_refreshable",
+            AppError::Gone(_) => "gone",
+            AppError::ReloginRequired(_) => "relogin_required",
+            AppError::OAuth(_) => "github_rejected",
+            AppError::GitHub(_) => "github_unavailable",
+            AppError::StoreUnavailable(_) => "storage_unavailable",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            AppError::BadRequest(x)
+            | AppError::Unauthorized(x)
+            | AppError::Forbidden(x)
+            | AppError::NotAllowed(x)
+            | AppError::NotLoggedIn(x)
+            | AppError::NotFound(x)
+            | AppError::ReplayedRequest(x)
+            | AppError::NotRefreshable(x)
+            | AppError::Gone(x)
+            | AppError::ReloginRequired(x)
+            | AppError::GitHub(x)
+            | AppError::StoreUnavailable(x)
+            | AppError::Internal(x) => x,
+            AppError::OAuth(x) => x.error_description.as_deref().unwrap_or(&x.error),
+        }
+    }
+
+    /// What the caller gets to see.
+    pub fn message(&self) -> &str {
+        match self {
+            // These may carry URLs with client secrets or internals of the store.
+            AppError::GitHub(_) => "Failed to talk to GitHub",
+            AppError::StoreUnavailable(_) => "Credential store is unavailable",
+            AppError::Internal(_) => "Internal error",
+            x => x.detail(),
+        }
+    }
+
+    /// Log the error under a fresh request id, which is returned.
+    pub fn log(&self) -> String {
+        let request_id = random_id();
+
+        if self.status().is_server_error() {
+            error!("[{request_id}] {self}");
+        } else {
+            warn!("[{request_id}] {self}");
+        }
+
+        request_id
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::OAuth(x) => write!(f, "{}: {} ({})", self.code(), x.error, self.detail()),
+            x => write!(f, "{}: {}", x.code(), x.detail()),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let request_id = self.log();
+        let status = self.status();
+
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id: &request_id,
+            github: match &self {
+                AppError::OAuth(x) => Some(x),
+                _ => None,
+            },
+        };
+
+        let mut resp = (status, Json(body)).into_response();
+        let headers = resp.headers_mut();
+        headers.insert("cache-control", HeaderValue::from_static("no-cache"));
+        if let Ok(x) = HeaderValue::from_str(&request_id) {
+            headers.insert("x-request-id", x);
+        }
+
+        resp
+    }
+}
@@ -0,0 +1,102 @@
+//! Index of GitHub accounts to the telegram users linked to them, so the bot
+//! can find who to notify about a PR.
+//!
+//! Each account is indexed both by numeric id and by (lowercased) login,
+//! since GitHub mostly hands out logins but only ids survive a rename.
+
+use std::io;
+
+use tracing::{info, warn};
+
+use crate::{
+    credential::{GitHubIdentity, StoredCredential},
+    credential_aad, crypto,
+    store::CredentialStore,
+};
+
+pub fn by_id(github_id: u64) -> String {
+    format!("id:{github_id}")
+}
+
+pub fn by_login(login: &str) -> String {
+    format!("login:{}", login.to_lowercase())
+}
+
+fn keys(identity: &GitHubIdentity) -> [String; 2] {
+    [by_id(identity.id), by_login(&identity.login)]
+}
+
+pub async fn link(
+    store: &dyn CredentialStore,
+    id: &str,
+    identity: &GitHubIdentity,
+) -> io::Result<()> {
+    for key in keys(identity) {
+        store.add_link(&key, id).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn unlink(
+    store: &dyn CredentialStore,
+    id: &str,
+    identity: &GitHubIdentity,
+) -> io::Result<()> {
+    for key in keys(identity) {
+        store.remove_link(&key, id).await?;
+    }
+
+    Ok(())
+}
+
+/// Move `id` from the account it was linked to before to the one of its new
+/// credential. An unknown new account counts as a different one.
+pub async fn relink(
+    store: &dyn CredentialStore,
+    id: &str,
+    old: Option<&GitHubIdentity>,
+    new: Option<&GitHubIdentity>,
+) -> io::Result<()> {
+    if let Some(old) = old {
+        if new.is_none_or(|x| x.id != old.id || !x.login.eq_ignore_ascii_case(&old.login)) {
+            unlink(store, id, old).await?;
+        }
+    }
+
+    if let Some(new) = new {
+        link(store, id, new).await?;
+    }
+
+    Ok(())
+}
+
+/// Index every stored credential, e.g. for credentials from before the index existed.
+pub async fn rebuild(store: &dyn CredentialStore) -> io::Result<()> {
+    for id in store.list().await? {
+        let Some(s) = store.get(&id).await? else {
+            continue;
+        };
+
+        let credential = crypto::open(&credential_aad(&id), &s)
+            .and_then(|s| StoredCredential::decode(&s).map_err(io::Error::other));
+
+        let identity = match credential {
+            Ok((x, _)) => x.identity,
+            Err(e) => {
+                warn!("Skipping {id}: {e}");
+                continue;
+            }
+        };
+
+        match identity {
+            Some(identity) => {
+                link(store, &id, &identity).await?;
+                info!("Linked {id} to {}", identity.login);
+            }
+            None => warn!("Skipping {id}: its GitHub account is unknown"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Redis key layout. Everything lives under `KEY_PREFIX` (`minzhengbu:v1` by
+//! default) so minzhengbu can share a database with other services.
+
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, AsyncCommands, RedisResult};
+use tracing::{info, warn};
+
+use crate::{credential::StoredCredential, credential_aad, crypto};
+
+static KEY_PREFIX: Lazy<String> =
+    Lazy::new(|| std::env::var("KEY_PREFIX").unwrap_or_else(|_| "minzhengbu:v1".to_string()));
+
+/// Credential of a telegram user.
+pub fn credential(telegram_id: &str) -> String {
+    format!("{}:cred:tg:{telegram_id}", *KEY_PREFIX)
+}
+
+pub fn credential_pattern() -> String {
+    credential("*")
+}
+
+/// Inverse of [`credential`].
+pub fn credential_id(key: &str) -> Option<&str> {
+    key.strip_prefix(&*KEY_PREFIX)?.strip_prefix(":cred:tg:")
+}
+
+/// Login waiting for the user to open the telegram link.
+pub fn pending(rid: &str) -> String {
+    format!("{}:pending:{rid}", *KEY_PREFIX)
+}
+
+/// OAuth `state` handed out by `/authorize`.
+pub fn oauth_state(state: &str) -> String {
+    format!("{}:state:{state}", *KEY_PREFIX)
+}
+
+/// Device authorization in progress.
+pub fn device(handle: &str) -> String {
+    format!("{}:device:{handle}", *KEY_PREFIX)
+}
+
+/// Nonce of a signed service request.
+pub fn nonce(nonce: &str) -> String {
+    format!("{}:nonce:{nonce}", *KEY_PREFIX)
+}
+
+/// Lease of the replica refreshing the credential of a telegram user.
+pub fn refresh_lease(telegram_id: &str) -> String {
+    format!("{}:lease:{telegram_id}", *KEY_PREFIX)
+}
+
+/// Set of telegram ids linked to a GitHub account, see `index`.
+pub fn github_link(key: &str) -> String {
+    format!("{}:github:{key}", *KEY_PREFIX)
+}
+
+/// Sorted set of telegram id -> unix time at which its token should be refreshed.
+pub fn refresh_schedule() -> String {
+    format!("{}:refresh_schedule", *KEY_PREFIX)
+}
+
+/// Move credentials written before the key layout existed, stored under the
+/// bare telegram id, to where they belong now. Other keys may belong to
+/// another service sharing the database and are left alone.
+pub async fn migrate(conn: &mut MultiplexedConnection) -> RedisResult<()> {
+    let mut keys = vec![];
+    {
+        let mut iter = conn.scan::<String>().await?;
+        while let Some(key) = iter.next_item().await {
+            if !key.starts_with(&*KEY_PREFIX) {
+                keys.push(key);
+            }
+        }
+    }
+
+    for key in keys {
+        if !is_legacy_credential(conn, &key).await {
+            warn!("Leaving {key} alone: it does not look like ours");
+            continue;
+        }
+        let new_key = credential(&key);
+
+        // RENAMENX keeps the TTL and never clobbers a key written since the upgrade.
+        if conn.rename_nx(&key, &new_key).await? {
+            info!("Renamed {key} to {new_key}");
+        } else {
+            warn!("Leaving {key} alone: {new_key} already exists");
+        }
+    }
+
+    Ok(())
+}
+
+async fn is_legacy_credential(conn: &mut MultiplexedConnection, key: &str) -> bool {
+    let Ok(s) = conn.get::<_, String>(key).await else {
+        return false;
+    };
+
+    crypto::open(&credential_aad(key), &s)
+        .ok()
+        .is_some_and(|s| StoredCredential::decode(&s).is_ok())
+}
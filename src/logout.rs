@@ -0,0 +1,118 @@
+use axum::{extract::Query, http::HeaderMap, response::IntoResponse, Json};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    auth::{Scope, ServiceAuth},
+    error::AppError,
+    index, load_credential, refresh_user_token, store,
+    store::CredentialStore,
+    store_error, TelegramId, CLIENT_ID, CLIENT_SECRET,
+};
+
+#[derive(Serialize, Debug)]
+pub struct Unlinked {
+    unlinked: bool,
+    /// Whether GitHub confirmed that the token is gone.
+    revoked: bool,
+}
+
+pub async fn logout(
+    auth: ServiceAuth,
+    Query(payload): Query<TelegramId>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenRevoke)?;
+    let revoked = logout_user(store()?, &payload.account()?).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    let msg = if revoked {
+        "Successful logout"
+    } else {
+        "Successful logout, but GitHub did not confirm the revocation"
+    };
+
+    Ok((headers, msg.to_string()))
+}
+
+/// Same as `logout`, answering in JSON for the bot.
+pub async fn unlink(
+    auth: ServiceAuth,
+    Query(payload): Query<TelegramId>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenRevoke)?;
+    let revoked = logout_user(store()?, &payload.account()?).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((
+        headers,
+        Json(Unlinked {
+            unlinked: true,
+            revoked,
+        }),
+    ))
+}
+
+/// Revoke the GitHub token of `id` and forget its credential. Only this token
+/// goes, other accounts linked to the same GitHub user keep theirs. The
+/// credential is removed even if GitHub can't be reached; returns whether
+/// revocation succeeded.
+pub async fn logout_user(store: &dyn CredentialStore, id: &str) -> Result<bool, AppError> {
+    // GitHub needs a live access token to revoke, but a broken
+    // refresh token must not keep the user from logging out.
+    let credential = match refresh_user_token(store, id, Some(0)).await {
+        Ok(x) => x,
+        Err(AppError::NotLoggedIn(x)) => return Err(AppError::NotLoggedIn(x)),
+        Err(_) => load_credential(store, id).await?,
+    };
+    let revoked = revoke_token(&credential.token.access_token).await;
+
+    store.delete(id).await.map_err(|e| store_error(&e))?;
+    store.unschedule(id).await.map_err(|e| store_error(&e))?;
+    if let Some(identity) = &credential.identity {
+        index::unlink(store, id, identity)
+            .await
+            .map_err(|e| store_error(&e))?;
+    }
+    info!("Logged out {id}");
+
+    Ok(revoked)
+}
+
+/// Revoke every token of the GitHub user owning `access_token`.
+pub async fn revoke_grant(access_token: &str) -> bool {
+    revoke("grant", access_token).await
+}
+
+/// Revoke `access_token` alone.
+pub async fn revoke_token(access_token: &str) -> bool {
+    revoke("token", access_token).await
+}
+
+async fn revoke(what: &str, access_token: &str) -> bool {
+    let client = reqwest::Client::new();
+    let resp = client
+        .delete(format!(
+            "https://api.github.com/applications/{}/{what}",
+            *CLIENT_ID
+        ))
+        .basic_auth(&*CLIENT_ID, Some(&*CLIENT_SECRET))
+        .header("accept", "application/vnd.github+json")
+        .header("user-agent", "minzhengbu")
+        .json(&json!({ "access_token": access_token }))
+        .send()
+        .await
+        .and_then(|x| x.error_for_status());
+
+    match resp {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Failed to revoke github {what}: {e}");
+            false
+        }
+    }
+}
@@ -1,56 +1,130 @@
-use std::{error::Error, io};
+mod allowlist;
+mod app;
+mod auth;
+mod bot;
+mod credential;
+mod crypto;
+mod device;
+mod error;
+mod index;
+mod keys;
+mod logout;
+mod miniapp;
+mod page;
+mod pending;
+mod pkce;
+mod refresh;
+mod state;
+mod store;
+mod telegram;
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
     extract::Query,
-    http::{HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
+    http::{header::SET_COOKIE, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     routing::get,
     Json, Router,
 };
-use tracing::{log::error, warn};
+use tracing::{info, log::error, warn};
 
 use dashmap::DashMap;
 use once_cell::sync::{Lazy, OnceCell};
 use rand::{distributions::Alphanumeric, Rng};
-use redis::{aio::MultiplexedConnection, AsyncCommands};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
+use crate::{
+    auth::{Scope, ServiceAuth},
+    credential::{GitHubIdentity, StoredCredential},
+    error::{AppError, OAuthError},
+    page::{Lang, Page},
+    pending::PendingLogin,
+    state::{Flow, LoginState},
+    store::{CredentialStore, MemoryStore, RedisStore, SqliteStore},
+};
+
 #[derive(Deserialize, Debug)]
 struct CallbackLoginArgs {
     code: String,
+    state: Option<String>,
 }
 
+/// Token issued by GitHub. Classic OAuth Apps, and GitHub Apps with token
+/// expiration disabled, get tokens without expiry or refresh token.
 #[derive(Deserialize, Serialize, Debug)]
 struct CallbackSecondLoginArgs {
     access_token: String,
-    expires_in: i64,
-    refresh_token: String,
-    refresh_token_expires_in: i64,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    refresh_token_expires_in: Option<i64>,
     scope: String,
     token_type: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct AuthorizeArgs {
+    #[serde(default)]
+    flow: Flow,
+    telegram_id: Option<String>,
+    bot: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CliLoginArgs {
+    code: String,
+    state: Option<String>,
+    code_verifier: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct TelegramInfo {
     telegram_id: String,
     rid: String,
+    bot: Option<String>,
 }
 
-static TEMP_MAP: Lazy<DashMap<String, CallbackSecondLoginArgs>> = Lazy::new(DashMap::new);
-
 static CLIENT_ID: Lazy<String> =
     Lazy::new(|| std::env::var("GITHUB_CLIENT_ID").expect("GITHUB_CLIENT_ID is not set"));
 static CLIENT_SECRET: Lazy<String> =
     Lazy::new(|| std::env::var("GITHUB_CLIENT_SECRET").expect("GITHUB_CLIENT_SECRET is not set"));
 static REDIRECT_URL: Lazy<String> =
     Lazy::new(|| std::env::var("REDIRECT_URL").expect("REDIRECT_URL is not set"));
+static CLI_REDIRECT_URL: Lazy<String> =
+    Lazy::new(|| std::env::var("CLI_REDIRECT_URL").unwrap_or_else(|_| REDIRECT_URL.clone()));
+static STORAGE: Lazy<String> =
+    Lazy::new(|| std::env::var("STORAGE").unwrap_or_else(|_| "redis".to_string()));
 static REDIS: Lazy<String> = Lazy::new(|| std::env::var("REDIS").expect("REDIS is not set"));
-static SECRET: Lazy<String> = Lazy::new(|| std::env::var("SECRET").expect("SECRET is not set"));
+static SQLITE_PATH: Lazy<String> =
+    Lazy::new(|| std::env::var("SQLITE_PATH").unwrap_or_else(|_| "minzhengbu.db".to_string()));
 static LOCAL_URL: Lazy<String> =
     Lazy::new(|| std::env::var("LOCAL_URL").expect("LOCAL_URL is not set"));
+/// Where the Telegram Login Widget sends the user, `/login_widget` next to
+/// `/login` unless set.
+static WIDGET_URL: Lazy<String> = Lazy::new(|| {
+    std::env::var("WIDGET_URL").unwrap_or_else(|_| {
+        sibling_url(&REDIRECT_URL, "login_widget")
+            .expect("REDIRECT_URL is not a URL, set WIDGET_URL")
+    })
+});
+
+/// `get_token` refreshes tokens that expire within this many seconds.
+static TOKEN_MIN_TTL: Lazy<i64> = Lazy::new(|| env_or("TOKEN_MIN_TTL", 300));
+
+static STORE: OnceCell<Box<dyn CredentialStore>> = OnceCell::new();
 
-static DB_CONN: OnceCell<MultiplexedConnection> = OnceCell::new();
+/// Refresh tokens are single use, so refreshes of the same user must not overlap.
+static REFRESH_LOCKS: Lazy<DashMap<String, Arc<Mutex<()>>>> = Lazy::new(DashMap::new);
 
 #[tokio::main]
 async fn main() {
@@ -72,71 +146,116 @@ async fn main() {
     let _ = &*CLIENT_ID;
     let _ = &*CLIENT_SECRET;
     let _ = &*REDIRECT_URL;
-    let _ = &*SECRET;
+    let _ = &*CLI_REDIRECT_URL;
+    let _ = &*WIDGET_URL;
+    auth::init();
+    bot::init();
+    page::init();
+    allowlist::init();
+
+    let store: Box<dyn CredentialStore> = match STORAGE.as_str() {
+        "redis" => Box::new(RedisStore::new(redis_connection().await)),
+        "sqlite" => Box::new(
+            SqliteStore::open(&SQLITE_PATH)
+                .unwrap_or_else(|e| panic!("Failed to open {}: {e}", *SQLITE_PATH)),
+        ),
+        "memory" => {
+            warn!("Using in-memory storage, everything is lost on restart");
+            Box::<MemoryStore>::default()
+        }
+        x => panic!("Unknown STORAGE: {x}"),
+    };
 
-    let client = redis::Client::open(REDIS.as_str()).expect("Failed to connect redis database");
+    match std::env::args().nth(1).as_deref() {
+        Some("reencrypt") => {
+            if !crypto::enabled() {
+                error!("TOKEN_KEYS is not set, nothing to encrypt with");
+                std::process::exit(1);
+            }
 
-    let connect = client
-        .get_multiplexed_tokio_connection()
-        .await
-        .expect("Failed to get multiplexed connection");
+            reencrypt(store.as_ref())
+                .await
+                .expect("Failed to re-encrypt");
+            return;
+        }
+        Some("reindex") => {
+            index::rebuild(store.as_ref())
+                .await
+                .expect("Failed to rebuild the GitHub index");
+            return;
+        }
+        Some("migrate-keys") => {
+            let mut conn = redis_connection().await;
+            keys::migrate(&mut conn)
+                .await
+                .expect("Failed to migrate keys");
+            return;
+        }
+        _ => {}
+    }
+
+    if !crypto::enabled() {
+        warn!("TOKEN_KEYS is not set, tokens will be stored unencrypted");
+    }
 
-    DB_CONN.get_or_init(|| connect);
+    let store = STORE.get_or_init(|| store).as_ref();
+    refresh::spawn(store);
 
     // build our application with a route
-    let app = Router::new()
+    let mut app = Router::new()
         // `GET /` goes to `root`
+        .route("/authorize", get(authorize))
         .route("/login", get(login))
         .route("/login_cli", get(login_cli))
+        .route("/login_device", get(device::login_device))
+        .route("/login_device_poll", get(device::login_device_poll))
         .route("/login_from_telegram", get(login_from_telegram))
+        .route("/login_widget", get(login_widget))
+        .route("/miniapp_login", get(miniapp::miniapp_login))
+        .route("/miniapp_bind", get(miniapp::miniapp_bind))
+        .route("/miniapp_status", get(miniapp::miniapp_status))
         .route("/get_token", get(get_token))
-        .route("/refresh_token", get(refresh_token));
+        .route("/get_identity", get(get_identity))
+        .route("/lookup", get(lookup))
+        .route("/refresh_token", get(refresh_token))
+        .route("/logout", get(logout::logout))
+        .route("/unlink", get(logout::unlink));
+
+    if app::enabled() {
+        app = app.route("/installation_token", get(app::installation_token));
+    }
 
     let listener = tokio::net::TcpListener::bind(&*LOCAL_URL).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn refresh_token(
-    headers: HeaderMap,
-    Query(payload): Query<TelegramId>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let TelegramId { id } = payload;
+async fn redis_connection() -> redis::aio::MultiplexedConnection {
+    let client = redis::Client::open(REDIS.as_str()).expect("Failed to connect redis database");
 
-    if !secret_check(&headers) {
-        error!("Auth failed: secret not match");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+    client
+        .get_multiplexed_tokio_connection()
+        .await
+        .expect("Failed to get multiplexed connection")
+}
 
-    let mut conn = DB_CONN
+fn store() -> Result<&'static dyn CredentialStore, AppError> {
+    STORE
         .get()
-        .ok_or_else(|| {
-            let err = io::Error::new(io::ErrorKind::Other, "Database connection does not exist");
-            error(&err)
-        })?
-        .to_owned();
+        .map(|x| x.as_ref())
+        .ok_or_else(|| AppError::StoreUnavailable("Credential store does not exist".into()))
+}
 
-    let res: Result<String, redis::RedisError> = conn.get(&id).await;
-    let s = res.map_err(|e| error(&e))?;
-    let res: CallbackSecondLoginArgs = serde_json::from_str(&s).map_err(|e| error(&e))?;
+async fn refresh_token(
+    auth: ServiceAuth,
+    Query(payload): Query<TelegramId>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenRefresh)?;
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .post("https://github.com/login/oauth/access_token")
-        .query(&[
-            ("client_id", CLIENT_ID.as_str()),
-            ("client_secret", &*CLIENT_SECRET),
-            ("grant_type", "refresh_token"),
-            ("refresh_token", &res.refresh_token),
-        ])
-        .send()
-        .await
-        .and_then(|x| x.error_for_status())
-        .map_err(|e| error(&e))?;
+    let id = payload.account()?;
 
-    let login_args = format_github_query(resp.text().await.map_err(|e| error(&e))?)?;
+    let store = store()?;
 
-    let s = serde_json::to_string(&login_args).map_err(|e| error(&e))?;
-    conn.set(id, s).await.map_err(|e| error(&e))?;
+    refresh_user_token(store, &id, None).await?;
 
     let mut headers = HeaderMap::new();
     headers.insert("cache-control", "no-cache".parse().unwrap());
@@ -144,46 +263,323 @@ async fn refresh_token(
     Ok((headers, "Successful refresh".to_string()))
 }
 
-fn secret_check(headers: &HeaderMap) -> bool {
-    let secret = headers.get("secret");
+/// Exchange the stored refresh token of `id` for a new token pair and store it.
+///
+/// With `min_ttl`, a token that is still valid for at least that many seconds
+/// (e.g. because a concurrent caller has just refreshed it) is returned as is.
+async fn refresh_user_token(
+    store: &dyn CredentialStore,
+    id: &str,
+    min_ttl: Option<i64>,
+) -> Result<StoredCredential, AppError> {
+    let lock = REFRESH_LOCKS.entry(id.to_string()).or_default().clone();
+    let guard = lock.lock().await;
+
+    // The lock only covers this process, the lease covers the other replicas.
+    let res = match refresh::claim_lease(store, id).await {
+        Ok(()) => {
+            let res = exchange_refresh_token(store, id, min_ttl).await;
+            refresh::release_lease(store, id).await;
+            res
+        }
+        Err(e) => Err(store_error(&e)),
+    };
 
-    secret
-        .and_then(|x| x.to_str().ok())
-        .map(|x| x == *SECRET)
-        .unwrap_or(false)
+    drop(guard);
+    drop(lock);
+    REFRESH_LOCKS.remove_if(id, |_, x| Arc::strong_count(x) == 1);
+
+    match res {
+        Ok(x) if x.needs_relogin => Err(relogin_required(id)),
+        x => x,
+    }
 }
 
-async fn login_from_telegram(
-    Query(payload): Query<TelegramInfo>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let TelegramInfo { telegram_id, rid } = payload;
+async fn exchange_refresh_token(
+    store: &dyn CredentialStore,
+    id: &str,
+    min_ttl: Option<i64>,
+) -> Result<StoredCredential, AppError> {
+    let (res, sealed) = load_sealed_credential(store, id).await?;
 
-    let access_info = TEMP_MAP.get(&rid).ok_or_else(|| {
-        let err = io::Error::new(
-            io::ErrorKind::Other,
-            format!("Could not find telegram access info by id: {rid}"),
-        );
-        error!("{err}");
-        StatusCode::NOT_FOUND
-    })?;
+    if res.needs_relogin {
+        return Err(relogin_required(id));
+    }
 
-    let mut conn = DB_CONN
-        .get()
-        .ok_or_else(|| {
-            let err = io::Error::new(
-                io::ErrorKind::Other,
-                "Could not open redis database connection",
-            );
-            error(&err)
-        })?
-        .to_owned();
+    if min_ttl.is_some_and(|x| res.access_expires_at.is_none_or(|at| at - now() > x)) {
+        return Ok(res);
+    }
 
-    let s = serde_json::to_string(access_info.value()).map_err(|e| error(&e))?;
+    let Some(refresh_token) = &res.token.refresh_token else {
+        return Err(AppError::NotRefreshable(format!(
+            "Token of {id} has no refresh token"
+        )));
+    };
 
-    conn.set(telegram_id, s).await.map_err(|e| error(&e))?;
+    let resp = request_token(&[
+        ("client_id", CLIENT_ID.as_str()),
+        ("client_secret", &*CLIENT_SECRET),
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ])
+    .await;
+
+    let login_args = match resp {
+        Err(AppError::OAuth(e)) if e.error == "bad_refresh_token" => {
+            if mark_needs_relogin(store, id, refresh_token).await? {
+                return Err(relogin_required(id));
+            }
 
-    drop(access_info);
-    TEMP_MAP.remove(&rid);
+            // Replaced meanwhile, e.g. by a new login.
+            return load_credential(store, id).await;
+        }
+        x => x?,
+    };
+    let identity = match res.identity {
+        Some(x) => Some(x),
+        None => fetch_identity(&login_args.access_token).await,
+    };
+    let credential = StoredCredential::issue(login_args, identity);
+
+    // A login may have replaced the credential while GitHub was asked, keep that one.
+    if !replace_credential(store, id, &sealed, &credential).await? {
+        // Unless it was only flagged by somebody whose use of the same refresh
+        // token GitHub rejected, because we spent it first.
+        let (current, sealed) = load_sealed_credential(store, id).await?;
+        if current.needs_relogin
+            && current.token.refresh_token.as_deref() == Some(refresh_token.as_str())
+            && replace_credential(store, id, &sealed, &credential).await?
+        {
+            return Ok(credential);
+        }
+
+        info!("Credential of {id} changed during its refresh, dropping the refreshed token");
+        return load_credential(store, id).await;
+    }
+
+    Ok(credential)
+}
+
+/// Flag the credential of `id` so it is no longer refreshed, unless it has
+/// been replaced (e.g. by a new login) since `refresh_token` was read.
+/// Returns whether it was flagged.
+async fn mark_needs_relogin(
+    store: &dyn CredentialStore,
+    id: &str,
+    refresh_token: &str,
+) -> Result<bool, AppError> {
+    let updated = update_credential(store, id, |x| {
+        if x.token.refresh_token.as_deref() != Some(refresh_token) {
+            return false;
+        }
+
+        x.needs_relogin = true;
+        true
+    })
+    .await?;
+
+    if updated {
+        warn!("GitHub rejected the refresh token of {id}, it needs to log in again");
+        store.unschedule(id).await.map_err(|e| store_error(&e))?;
+    }
+
+    Ok(updated)
+}
+
+/// Change the stored credential of `id` in place. `f` returns whether to
+/// write it back; nothing is written if the credential changed meanwhile.
+/// Returns whether it was written.
+async fn update_credential(
+    store: &dyn CredentialStore,
+    id: &str,
+    f: impl FnOnce(&mut StoredCredential) -> bool,
+) -> Result<bool, AppError> {
+    let Some(sealed) = store.get(id).await.map_err(|e| store_error(&e))? else {
+        return Ok(false);
+    };
+    let s = crypto::open(&credential_aad(id), &sealed).map_err(|e| error(&e))?;
+    let (mut credential, _) = StoredCredential::decode(&s).map_err(|e| error(&e))?;
+
+    if !f(&mut credential) {
+        return Ok(false);
+    }
+
+    let s = serde_json::to_string(&credential).map_err(|e| error(&e))?;
+    let s = crypto::seal(&credential_aad(id), &s).map_err(|e| error(&e))?;
+
+    store
+        .compare_and_swap(id, Some(&sealed), &s)
+        .await
+        .map_err(|e| store_error(&e))
+}
+
+fn relogin_required(id: &str) -> AppError {
+    AppError::ReloginRequired(format!("Telegram user {id} needs to log in again"))
+}
+
+/// Load the credential of `id`, upgrading it in place if it is still in the
+/// legacy format or not sealed with the active key.
+async fn load_credential(
+    store: &dyn CredentialStore,
+    id: &str,
+) -> Result<StoredCredential, AppError> {
+    Ok(load_sealed_credential(store, id).await?.0)
+}
+
+/// Like [`load_credential`], also returning the value it was read from, for
+/// a later `compare_and_swap`.
+async fn load_sealed_credential(
+    store: &dyn CredentialStore,
+    id: &str,
+) -> Result<(StoredCredential, String), AppError> {
+    let sealed = store
+        .get(id)
+        .await
+        .map_err(|e| store_error(&e))?
+        .ok_or_else(|| AppError::NotLoggedIn(format!("Telegram user {id} has not logged in")))?;
+    let s = crypto::open(&credential_aad(id), &sealed).map_err(|e| error(&e))?;
+    let (credential, legacy) = StoredCredential::decode(&s).map_err(|e| error(&e))?;
+
+    if legacy || crypto::needs_reseal(&sealed) {
+        let s = serde_json::to_string(&credential).map_err(|e| error(&e))?;
+        let s = crypto::seal(&credential_aad(id), &s).map_err(|e| error(&e))?;
+
+        // Somebody else may be upgrading it at the same time, that's fine.
+        if store
+            .compare_and_swap(id, Some(&sealed), &s)
+            .await
+            .map_err(|e| store_error(&e))?
+        {
+            schedule_refresh(store, id, &credential).await?;
+            return Ok((credential, s));
+        }
+    }
+
+    Ok((credential, sealed))
+}
+
+/// Store the credential of `id`, schedule its background refresh and move
+/// it over to its GitHub account in the index.
+async fn store_credential(
+    store: &dyn CredentialStore,
+    id: &str,
+    credential: &StoredCredential,
+) -> Result<(), AppError> {
+    let previous = store.get(id).await.map_err(|e| store_error(&e))?;
+    let previous = previous.and_then(|s| {
+        let s = crypto::open(&credential_aad(id), &s).ok()?;
+        StoredCredential::decode(&s).ok()?.0.identity
+    });
+
+    let s = serde_json::to_string(credential).map_err(|e| error(&e))?;
+    let s = crypto::seal(&credential_aad(id), &s).map_err(|e| error(&e))?;
+    store.put(id, &s).await.map_err(|e| store_error(&e))?;
+
+    index::relink(store, id, previous.as_ref(), credential.identity.as_ref())
+        .await
+        .map_err(|e| store_error(&e))?;
+    schedule_refresh(store, id, credential).await
+}
+
+/// Like [`store_credential`], but only if the stored credential is still
+/// `sealed`. Returns whether it was stored.
+async fn replace_credential(
+    store: &dyn CredentialStore,
+    id: &str,
+    sealed: &str,
+    credential: &StoredCredential,
+) -> Result<bool, AppError> {
+    let previous = crypto::open(&credential_aad(id), sealed)
+        .ok()
+        .and_then(|s| StoredCredential::decode(&s).ok()?.0.identity);
+
+    let s = serde_json::to_string(credential).map_err(|e| error(&e))?;
+    let s = crypto::seal(&credential_aad(id), &s).map_err(|e| error(&e))?;
+    if !store
+        .compare_and_swap(id, Some(sealed), &s)
+        .await
+        .map_err(|e| store_error(&e))?
+    {
+        return Ok(false);
+    }
+
+    index::relink(store, id, previous.as_ref(), credential.identity.as_ref())
+        .await
+        .map_err(|e| store_error(&e))?;
+    schedule_refresh(store, id, credential).await?;
+
+    Ok(true)
+}
+
+async fn schedule_refresh(
+    store: &dyn CredentialStore,
+    id: &str,
+    credential: &StoredCredential,
+) -> Result<(), AppError> {
+    match credential.refresh_at() {
+        Some(expires_at) => refresh::schedule(store, id, expires_at)
+            .await
+            .map_err(|e| store_error(&e)),
+        None => Ok(()),
+    }
+}
+
+fn credential_aad(id: &str) -> String {
+    format!("cred:{id}")
+}
+
+/// Seal every stored credential with the active key, e.g. after rotating `TOKEN_KEY_ID`.
+async fn reencrypt(store: &dyn CredentialStore) -> io::Result<()> {
+    for id in store.list().await? {
+        let Some(sealed) = store.get(&id).await? else {
+            continue;
+        };
+
+        if !crypto::needs_reseal(&sealed) {
+            continue;
+        }
+
+        let resealed = match crypto::open(&credential_aad(&id), &sealed)
+            .and_then(|s| crypto::seal(&credential_aad(&id), &s))
+        {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Skipping {id}: {e}");
+                continue;
+            }
+        };
+
+        // Only replace a value if nobody has written it in the meantime.
+        if store
+            .compare_and_swap(&id, Some(&sealed), &resealed)
+            .await?
+        {
+            info!("Re-encrypted {id}");
+        } else {
+            warn!("Skipping {id}: it changed while re-encrypting");
+        }
+    }
+
+    Ok(())
+}
+
+/// Claim a pending login for the user who opened the `/start <rid>` link of
+/// the bot. Only the bot knows who that was, so this takes a service request;
+/// browsers bind through `/login_widget` or the Mini App instead.
+async fn login_from_telegram(
+    auth: ServiceAuth,
+    Query(payload): Query<TelegramInfo>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenWrite)?;
+
+    let TelegramInfo {
+        telegram_id,
+        rid,
+        bot,
+    } = payload;
+
+    let bot = bot.as_deref().map(|x| bot::resolve(Some(x))).transpose()?;
+    bind_pending(store()?, &rid, bot, &telegram_id).await?;
 
     let mut headers = HeaderMap::new();
     headers.insert("cache-control", "no-cache".parse().unwrap());
@@ -191,73 +587,252 @@ async fn login_from_telegram(
     Ok((headers, "Successful login".to_string()))
 }
 
-async fn login(Query(payload): Query<CallbackLoginArgs>) -> Result<impl IntoResponse, StatusCode> {
-    let CallbackLoginArgs { code } = payload;
+/// Where the Telegram Login Widget on the login page sends the user. Unlike
+/// `login_from_telegram`, the telegram id is signed by Telegram.
+async fn login_widget(
+    headers: HeaderMap,
+    Query(fields): Query<BTreeMap<String, String>>,
+) -> Response {
+    let lang = Lang::from_headers(&headers);
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .post("https://github.com/login/oauth/access_token")
-        .query(&[
-            ("client_id", &*CLIENT_ID),
-            ("client_secret", &*CLIENT_SECRET),
-            ("code", &code),
-            ("redirect_uri", &*REDIRECT_URL),
-        ])
-        .send()
-        .await
-        .and_then(|x| x.error_for_status())
-        .map_err(|e| error(&e))?;
+    match bind_widget(fields).await {
+        Ok(()) => page::render(lang, Page::Success, StatusCode::OK, &[]),
+        Err(e) => page::error(lang, &e),
+    }
+}
 
-    let query = resp.text().await.map_err(|e| error(&e))?;
-    let login_args = format_github_query(query)?;
+async fn bind_widget(mut fields: BTreeMap<String, String>) -> Result<(), AppError> {
+    // Ours, added to `auth_url`, the rest is what Telegram signed.
+    let rid = fields
+        .remove("rid")
+        .ok_or_else(|| AppError::BadRequest("Missing rid".into()))?;
+    let bot = bot::resolve(fields.remove("bot").as_deref())?;
 
-    let s = tokio::task::spawn_blocking(|| {
-        let rng = rand::thread_rng();
-        let s: String = rng
-            .sample_iter(&Alphanumeric)
-            .take(20)
-            .map(char::from)
-            .collect();
+    let user = telegram::verify_widget(bot, fields)?;
+    let telegram_id = user
+        .get("id")
+        .ok_or_else(|| AppError::Unauthorized("Missing id".into()))?;
 
-        TEMP_MAP.insert(s.clone(), login_args);
+    bind_pending(store()?, &rid, Some(bot), telegram_id).await
+}
 
-        s
-    })
-    .await
-    .map_err(|e| error(&e))?;
+/// Store the pending login `rid` as the credential of `telegram_id`. `bot` is
+/// the bot the caller speaks for, if it said so.
+async fn bind_pending(
+    store: &dyn CredentialStore,
+    rid: &str,
+    bot: Option<&str>,
+    telegram_id: &str,
+) -> Result<(), AppError> {
+    let not_found = || AppError::NotFound(format!("Unknown or expired login {rid}"));
+
+    // Only claimed once it is known to be ours, so a wrong caller can't burn it.
+    let access_info = pending::get(store, rid).await?.ok_or_else(not_found)?;
+
+    if access_info
+        .telegram_id
+        .as_ref()
+        .is_some_and(|x| *x != telegram_id)
+    {
+        return Err(AppError::Forbidden(format!(
+            "Login {rid} was not started by telegram user {telegram_id}"
+        )));
+    }
+
+    let login_bot = bot::resolve(access_info.bot.as_deref())?;
+    if bot.is_some_and(|x| x != login_bot) {
+        return Err(AppError::Forbidden(format!(
+            "Login {rid} was started for bot {login_bot}"
+        )));
+    }
+
+    let access_info = pending::take(store, rid).await?.ok_or_else(not_found)?;
+
+    let id = bot::account(login_bot, telegram_id);
+    store_credential(store, &id, &access_info.credential).await
+}
+
+/// Start a login by sending the user to GitHub with a fresh `state`.
+async fn authorize(Query(payload): Query<AuthorizeArgs>) -> Result<impl IntoResponse, AppError> {
+    let AuthorizeArgs {
+        flow,
+        telegram_id,
+        bot,
+        code_challenge,
+        code_challenge_method,
+    } = payload;
+
+    // The CLI is a public client, so its code must be bound to the CLI instance.
+    if flow == Flow::Cli {
+        if code_challenge_method.as_deref() != Some("S256") {
+            return Err(AppError::BadRequest(
+                "CLI login requires code_challenge_method=S256".into(),
+            ));
+        }
+
+        if !code_challenge.as_deref().is_some_and(pkce::is_valid) {
+            return Err(AppError::BadRequest(
+                "CLI login requires a valid code_challenge".into(),
+            ));
+        }
+    }
+
+    // The CLI has PKCE instead.
+    let (browser, cookie) = match flow {
+        Flow::Web => {
+            let (hash, cookie) = state::bind_browser(REDIRECT_URL.starts_with("https://"));
+            (Some(hash), Some(cookie))
+        }
+        Flow::Cli => (None, None),
+    };
+
+    let login_state = LoginState {
+        flow,
+        telegram_id,
+        bot: Some(bot::resolve(bot.as_deref())?.to_string()),
+        code_challenge,
+        browser,
+    };
+    let url = authorize_url(store()?, &login_state).await?;
 
     let mut headers = HeaderMap::new();
     headers.insert("cache-control", "no-cache".parse().unwrap());
+    if let Some(cookie) = cookie {
+        headers.insert(SET_COOKIE, cookie.parse().map_err(|e| error(&e))?);
+    }
 
-    Ok((
-        headers,
-        Html::from(format!(
-            "<a href=\"https://t.me/aosc_buildit_bot?start={s}\">Please click on this link to complete authentication.</a>"
-        )),
-    ))
+    Ok((headers, Redirect::to(url.as_str())))
 }
 
-async fn login_cli(
-    Query(payload): Query<CallbackLoginArgs>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let CallbackLoginArgs { code } = payload;
+/// GitHub page that starts the login of `login_state`.
+async fn authorize_url(
+    store: &dyn CredentialStore,
+    login_state: &LoginState,
+) -> Result<reqwest::Url, AppError> {
+    let redirect_uri = match login_state.flow {
+        Flow::Web => &*REDIRECT_URL,
+        Flow::Cli => &*CLI_REDIRECT_URL,
+    };
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .post("https://github.com/login/oauth/access_token")
-        .query(&[
-            ("client_id", &*CLIENT_ID),
-            ("client_secret", &*CLIENT_SECRET),
-            ("code", &code),
-            ("redirect_uri", &*REDIRECT_URL),
-        ])
-        .send()
-        .await
-        .and_then(|x| x.error_for_status())
-        .map_err(|e| error(&e))?;
+    let state = state::mint(store, login_state).await?;
 
-    let query = resp.text().await.map_err(|e| error(&e))?;
-    let login_args = format_github_query(query)?;
+    let mut params = vec![
+        ("client_id", CLIENT_ID.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("state", &state),
+    ];
+
+    if let Some(code_challenge) = &login_state.code_challenge {
+        params.push(("code_challenge", code_challenge));
+        params.push(("code_challenge_method", "S256"));
+    }
+
+    reqwest::Url::parse_with_params("https://github.com/login/oauth/authorize", &params)
+        .map_err(|e| error(&e))
+}
+
+/// Where GitHub sends the user back to after a web login.
+async fn login(headers: HeaderMap, Query(payload): Query<CallbackLoginArgs>) -> Response {
+    let lang = Lang::from_headers(&headers);
+
+    match finish_login(&headers, payload).await {
+        Ok((bot, rid)) => {
+            let link = bot::start_link(bot, &rid);
+
+            if bot::token(bot).is_some() {
+                let auth_url = format!("{}?bot={bot}&rid={rid}", *WIDGET_URL);
+                page::render(
+                    lang,
+                    Page::LoginWidget,
+                    StatusCode::OK,
+                    &[
+                        ("link", &link),
+                        ("rid", &rid),
+                        ("bot", bot),
+                        ("auth_url", &auth_url),
+                    ],
+                )
+            } else {
+                page::render(
+                    lang,
+                    Page::Login,
+                    StatusCode::OK,
+                    &[("link", &link), ("rid", &rid), ("bot", bot)],
+                )
+            }
+        }
+        Err(e) => page::error(lang, &e),
+    }
+}
+
+/// Park the token of a web login, returning its bot and the id to claim it with.
+async fn finish_login(
+    headers: &HeaderMap,
+    payload: CallbackLoginArgs,
+) -> Result<(&'static str, String), AppError> {
+    let CallbackLoginArgs { code, state } = payload;
+
+    let store = store()?;
+
+    let login_state = state::consume(store, state.as_deref(), Flow::Web).await?;
+    state::check_browser(&login_state, headers)?;
+    let bot = bot::resolve(login_state.bot.as_deref())?;
+
+    let login_args = request_token(&[
+        ("client_id", &*CLIENT_ID),
+        ("client_secret", &*CLIENT_SECRET),
+        ("code", &code),
+        ("redirect_uri", &*REDIRECT_URL),
+    ])
+    .await?;
+    let identity = fetch_identity(&login_args.access_token).await;
+    allowlist::admit(&login_args.access_token, identity.as_ref()).await?;
+
+    let pending_login = PendingLogin {
+        credential: StoredCredential::issue(login_args, identity),
+        telegram_id: login_state.telegram_id,
+        bot: Some(bot.to_string()),
+    };
+
+    let rid = pending::put(store, &pending_login).await?;
+
+    Ok((bot, rid))
+}
+
+async fn login_cli(Query(payload): Query<CliLoginArgs>) -> Result<impl IntoResponse, AppError> {
+    let CliLoginArgs {
+        code,
+        state,
+        code_verifier,
+    } = payload;
+
+    let store = store()?;
+
+    let login_state = state::consume(store, state.as_deref(), Flow::Cli).await?;
+
+    let code_verifier = code_verifier
+        .filter(|x| pkce::is_valid(x))
+        .ok_or_else(|| AppError::BadRequest("Missing or malformed code_verifier".into()))?;
+
+    if !login_state
+        .code_challenge
+        .as_deref()
+        .is_some_and(|x| pkce::verify(&code_verifier, x))
+    {
+        return Err(AppError::Forbidden(
+            "code_verifier does not match code_challenge".into(),
+        ));
+    }
+
+    let login_args = request_token(&[
+        ("client_id", &*CLIENT_ID),
+        ("client_secret", &*CLIENT_SECRET),
+        ("code", &code),
+        ("redirect_uri", &*CLI_REDIRECT_URL),
+        ("code_verifier", &code_verifier),
+    ])
+    .await?;
+    allowlist::admit(&login_args.access_token, None).await?;
 
     let mut headers = HeaderMap::new();
     headers.insert("cache-control", "no-cache".parse().unwrap());
@@ -265,109 +840,407 @@ async fn login_cli(
     Ok((headers, Json(login_args)))
 }
 
-fn format_github_query(query: String) -> Result<CallbackSecondLoginArgs, StatusCode> {
-    let map = querify(&query);
-    let mut access_token = None;
-    let mut expires_in = None;
-    let mut refresh_token = None;
-    let mut refresh_token_expires_in = None;
-    let mut scope = None;
-    let mut token_type = None;
-    for (k, v) in map {
-        match k {
-            "access_token" => access_token = Some(v),
-            "expires_in" => expires_in = Some(v),
-            "refresh_token" => refresh_token = Some(v),
-            "refresh_token_expires_in" => refresh_token_expires_in = Some(v),
-            "scope" => scope = Some(v),
-            "token_type" => token_type = Some(v),
-            x => {
-                warn!("Has invalid key '{x}: {v}'");
-                continue;
-            }
+#[derive(Deserialize, Debug)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// Best effort: a failure here should not fail the login.
+async fn fetch_identity(access_token: &str) -> Option<GitHubIdentity> {
+    match try_fetch_identity(access_token).await {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("Failed to get github user: {e}");
+            None
         }
     }
+}
 
-    let login_args = CallbackSecondLoginArgs {
-        access_token: access_token
-            .ok_or_else(|| err_message("access_token does not exist"))?
-            .to_string(),
-        expires_in: expires_in
-            .ok_or_else(|| err_message("expires_in does not exist"))?
-            .parse::<i64>()
-            .map_err(|e| error(&e))?,
-        refresh_token: refresh_token
-            .ok_or_else(|| err_message("refresh_token does not exist"))?
-            .to_string(),
-        refresh_token_expires_in: refresh_token_expires_in
-            .ok_or_else(|| err_message("refresh_token_expires_in does not exist"))?
-            .parse::<i64>()
-            .map_err(|e| error(&e))?,
-        token_type: token_type
-            .ok_or_else(|| err_message("token_type does not exist"))?
-            .to_string(),
-        scope: scope
-            .ok_or_else(|| err_message("scope does not exist"))?
-            .to_string(),
+async fn try_fetch_identity(access_token: &str) -> reqwest::Result<GitHubIdentity> {
+    let client = reqwest::Client::new();
+    let mut identity: GitHubIdentity = client
+        .get("https://api.github.com/user")
+        .bearer_auth(access_token)
+        .header("user-agent", "minzhengbu")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if identity.email.is_some() {
+        return Ok(identity);
+    }
+
+    // Private emails are only listed here, and only with the `user:email` scope.
+    let emails = client
+        .get("https://api.github.com/user/emails")
+        .bearer_auth(access_token)
+        .header("user-agent", "minzhengbu")
+        .send()
+        .await
+        .and_then(|x| x.error_for_status());
+
+    let emails = match emails {
+        Ok(resp) => resp.json::<Vec<GitHubEmail>>().await,
+        Err(e) => Err(e),
     };
 
-    Ok(login_args)
-}
+    match emails {
+        Ok(emails) => {
+            identity.email = emails
+                .into_iter()
+                .find(|x| x.primary && x.verified)
+                .map(|x| x.email);
+        }
+        Err(e) => warn!(
+            "Failed to get emails of github user {}: {e}",
+            identity.login
+        ),
+    }
 
-fn err_message(err: &str) -> StatusCode {
-    error!("{err}");
+    Ok(identity)
+}
 
-    StatusCode::INTERNAL_SERVER_ERROR
+/// Answer of GitHub's token endpoint: a token, or the `error` fields if the
+/// grant was rejected (which still comes with status 200).
+#[derive(Deserialize, Debug)]
+struct GitHubTokenResponse {
+    access_token: Option<String>,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    refresh_token_expires_in: Option<i64>,
+    scope: Option<String>,
+    token_type: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+    error_uri: Option<String>,
+    /// New polling interval of a device login told to `slow_down`.
+    interval: Option<i64>,
 }
 
-fn querify(string: &str) -> Vec<(&str, &str)> {
-    let mut v = Vec::new();
-    for pair in string.split('&') {
-        let mut it = pair.split('=').take(2);
-        let kv = match (it.next(), it.next()) {
-            (Some(k), Some(v)) => (k, v),
-            _ => continue,
-        };
-        v.push(kv);
+impl GitHubTokenResponse {
+    /// Decode a `body` of `content_type`. JSON is asked for, but the
+    /// urlencoded form GitHub answers with by default is still understood in
+    /// case the header gets lost on the way.
+    fn parse(content_type: Option<&str>, body: &str) -> Result<Self, AppError> {
+        if content_type.is_some_and(|x| x.starts_with("application/json")) {
+            serde_json::from_str(body).map_err(|e| AppError::GitHub(e.to_string()))
+        } else {
+            serde_urlencoded::from_str(body).map_err(|e| AppError::GitHub(e.to_string()))
+        }
     }
 
-    v
+    fn into_token(self) -> Result<CallbackSecondLoginArgs, AppError> {
+        if let Some(error) = self.error {
+            return Err(AppError::OAuth(OAuthError {
+                error,
+                error_description: self.error_description,
+                error_uri: self.error_uri,
+            }));
+        }
+
+        let missing = |x: &str| AppError::GitHub(format!("{x} does not exist"));
+
+        Ok(CallbackSecondLoginArgs {
+            access_token: self.access_token.ok_or_else(|| missing("access_token"))?,
+            expires_in: self.expires_in,
+            refresh_token: self.refresh_token,
+            refresh_token_expires_in: self.refresh_token_expires_in,
+            scope: self.scope.unwrap_or_default(),
+            token_type: self.token_type.ok_or_else(|| missing("token_type"))?,
+        })
+    }
+}
+
+/// Redeem a grant (`code` or `refresh_token`) at GitHub's token endpoint.
+async fn request_token(params: &[(&str, &str)]) -> Result<CallbackSecondLoginArgs, AppError> {
+    fetch_token(params).await?.into_token()
+}
+
+/// Like [`request_token`], but leaves errors to the caller.
+async fn fetch_token(params: &[(&str, &str)]) -> Result<GitHubTokenResponse, AppError> {
+    let client = reqwest::Client::new();
+    let resp = client
+        .post("https://github.com/login/oauth/access_token")
+        .header("accept", "application/json")
+        .query(params)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| github_error(&e))?;
+
+    let content_type = resp
+        .headers()
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+    let body = resp.text().await.map_err(|e| github_error(&e))?;
+
+    GitHubTokenResponse::parse(content_type.as_deref(), &body)
 }
 
 #[derive(Deserialize, Debug)]
 struct TelegramId {
     id: String,
+    bot: Option<String>,
+}
+
+impl TelegramId {
+    /// Id the credential is stored under, see `bot`.
+    fn account(&self) -> Result<String, AppError> {
+        Ok(bot::account(bot::resolve(self.bot.as_deref())?, &self.id))
+    }
 }
 
 async fn get_token(
+    auth: ServiceAuth,
     Query(payload): Query<TelegramId>,
-    headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    if !secret_check(&headers) {
-        error!("Auth failed: secret not match");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenRead)?;
+    let store = store()?;
+
+    let id = payload.account()?;
+
+    let mut credential = load_credential(store, &id).await?;
+    if credential.needs_relogin {
+        return Err(relogin_required(&id));
     }
+    if credential
+        .access_expires_at
+        .is_some_and(|x| x - now() <= *TOKEN_MIN_TTL)
+    {
+        credential = refresh_user_token(store, &id, Some(*TOKEN_MIN_TTL)).await?;
+    }
+    let s = serde_json::to_string(&credential).map_err(|e| error(&e))?;
 
-    let mut conn = DB_CONN
-        .get()
-        .ok_or_else(|| {
-            let err = io::Error::new(io::ErrorKind::Other, "database connection does not exist");
-            error(&err)
-        })?
-        .to_owned();
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
 
-    let res: Result<String, redis::RedisError> = conn.get(payload.id).await;
+    Ok((headers, s))
+}
+
+/// GitHub account linked to a telegram user.
+async fn get_identity(
+    auth: ServiceAuth,
+    Query(payload): Query<TelegramId>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::TokenRead)?;
+    let store = store()?;
+    let id = &payload.account()?;
+
+    let credential = load_credential(store, id).await?;
+    let identity = match credential.identity {
+        Some(x) => x,
+        None => {
+            // Logged in while GitHub could not be asked, try again now.
+            let identity = try_fetch_identity(&credential.token.access_token)
+                .await
+                .map_err(|e| github_error(&e))?;
+            let access_token = credential.token.access_token;
+
+            let updated = update_credential(store, id, |x| {
+                if x.token.access_token != access_token {
+                    return false;
+                }
+
+                x.identity = Some(identity.clone());
+                true
+            })
+            .await?;
+
+            if updated {
+                index::link(store, id, &identity)
+                    .await
+                    .map_err(|e| store_error(&e))?;
+            }
+
+            identity
+        }
+    };
 
     let mut headers = HeaderMap::new();
     headers.insert("cache-control", "no-cache".parse().unwrap());
 
-    let s = res.map_err(|e| error(&e))?;
+    Ok((headers, Json(identity)))
+}
 
-    Ok((headers, s))
+#[derive(Deserialize, Debug)]
+struct LookupArgs {
+    /// GitHub login.
+    github: Option<String>,
+    github_id: Option<u64>,
+    bot: Option<String>,
 }
 
-fn error(err: &dyn Error) -> StatusCode {
-    error!("{err}");
+#[derive(Serialize, Debug)]
+struct Lookup {
+    telegram_ids: Vec<String>,
+}
+
+/// Telegram users linked to a GitHub account.
+async fn lookup(
+    auth: ServiceAuth,
+    Query(payload): Query<LookupArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::UserLookup)?;
+    let store = store()?;
+
+    let bot = bot::resolve(payload.bot.as_deref())?;
+    let key = match (payload.github_id, payload.github) {
+        (Some(x), _) => index::by_id(x),
+        (None, Some(x)) => index::by_login(&x),
+        (None, None) => return Err(AppError::BadRequest("Missing github or github_id".into())),
+    };
+
+    let accounts = store.links(&key).await.map_err(|e| store_error(&e))?;
+    let mut telegram_ids: Vec<String> = accounts
+        .iter()
+        .filter_map(|x| bot::telegram_id(bot, x))
+        .map(|x| x.to_string())
+        .collect();
+    telegram_ids.sort();
 
-    StatusCode::INTERNAL_SERVER_ERROR
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((headers, Json(Lookup { telegram_ids })))
+}
+
+fn random_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect()
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+/// `url` with its last path segment (ignoring a trailing slash) replaced by `name`.
+fn sibling_url(url: &str, name: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(url).ok()?;
+    let path = url.path().trim_end_matches('/');
+    let parent = path.rsplit_once('/').map_or("", |x| x.0).to_string();
+
+    url.set_path(&format!("{parent}/{name}"));
+    url.set_query(None);
+    url.set_fragment(None);
+
+    Some(url.to_string())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn error(err: &dyn Error) -> AppError {
+    AppError::Internal(err.to_string())
+}
+
+fn store_error(err: &io::Error) -> AppError {
+    AppError::StoreUnavailable(err.to_string())
+}
+
+fn github_error(err: &reqwest::Error) -> AppError {
+    AppError::GitHub(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widget_url_next_to_login() {
+        let url = |x| sibling_url(x, "login_widget").unwrap();
+
+        assert_eq!(
+            url("https://example.com/login"),
+            "https://example.com/login_widget"
+        );
+        assert_eq!(
+            url("https://example.com/login/"),
+            "https://example.com/login_widget"
+        );
+        assert_eq!(
+            url("https://example.com/auth/login?x=1#y"),
+            "https://example.com/auth/login_widget"
+        );
+        assert_eq!(
+            url("https://example.com/"),
+            "https://example.com/login_widget"
+        );
+        assert_eq!(sibling_url("not a url", "login_widget"), None);
+    }
+
+    #[test]
+    fn token_response_urlencoded() {
+        let body = "access_token=gho_abc%3D%3D&expires_in=28800&refresh_token=ghr_x=y\
+                    &refresh_token_expires_in=15897600&scope=repo%2Cuser&token_type=bearer";
+        let token = GitHubTokenResponse::parse(Some("application/x-www-form-urlencoded"), body)
+            .unwrap()
+            .into_token()
+            .unwrap();
+
+        assert_eq!(token.access_token, "gho_abc==");
+        assert_eq!(token.expires_in, Some(28800));
+        assert_eq!(token.refresh_token.as_deref(), Some("ghr_x=y"));
+        assert_eq!(token.refresh_token_expires_in, Some(15897600));
+        assert_eq!(token.scope, "repo,user");
+        assert_eq!(token.token_type, "bearer");
+
+        // No content type at all is urlencoded, too.
+        let token = GitHubTokenResponse::parse(None, body).unwrap();
+        assert_eq!(token.scope.as_deref(), Some("repo,user"));
+    }
+
+    #[test]
+    fn token_response_json() {
+        let body = r#"{"access_token":"gho_abc","scope":"repo,user","token_type":"bearer"}"#;
+        let token = GitHubTokenResponse::parse(Some("application/json; charset=utf-8"), body)
+            .unwrap()
+            .into_token()
+            .unwrap();
+
+        assert_eq!(token.access_token, "gho_abc");
+        assert_eq!(token.expires_in, None);
+        assert_eq!(token.refresh_token, None);
+        assert_eq!(token.refresh_token_expires_in, None);
+        assert_eq!(token.scope, "repo,user");
+
+        let res = GitHubTokenResponse::parse(Some("application/json"), "scope=repo");
+        assert!(matches!(res, Err(AppError::GitHub(_))));
+    }
+
+    #[test]
+    fn token_response_error() {
+        let body = "error=bad_refresh_token&error_description=The+refresh+token+passed+is+incorrect+or+expired.\
+                    &error_uri=https%3A%2F%2Fdocs.github.com";
+        let res = GitHubTokenResponse::parse(None, body).unwrap().into_token();
+
+        let Err(AppError::OAuth(e)) = res else {
+            panic!("expected an OAuth error, got {res:?}");
+        };
+        assert_eq!(e.error, "bad_refresh_token");
+        assert_eq!(
+            e.error_description.as_deref(),
+            Some("The refresh token passed is incorrect or expired.")
+        );
+        assert_eq!(e.error_uri.as_deref(), Some("https://docs.github.com"));
+
+        let res = GitHubTokenResponse::parse(None, "token_type=bearer")
+            .unwrap()
+            .into_token();
+        assert!(matches!(res, Err(AppError::GitHub(_))));
+    }
 }
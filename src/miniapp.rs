@@ -0,0 +1,135 @@
+//! Logins from a Telegram Mini App. Requests carry the `initData` Telegram
+//! hands the Mini App, which is signed with the bot token and so proves who
+//! the user is, without service credentials or the bot in between:
+//!
+//! - `/miniapp_login`: GitHub page that starts a web login for the user
+//! - `/miniapp_bind`: claim the pending login `rid`, by default the
+//!   `start_param` the Mini App was opened with
+//! - `/miniapp_status`: whether, and to which GitHub account, the user is linked
+//!
+//! `initData` goes in `Authorization: tma <initData>`, never in the URL, where
+//! access logs would keep it around for anyone to replay.
+
+use axum::{
+    extract::Query,
+    http::{header::AUTHORIZATION, HeaderMap},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    authorize_url, bind_pending, bot,
+    credential::GitHubIdentity,
+    error::AppError,
+    load_credential,
+    state::{Flow, LoginState},
+    store,
+    store::CredentialStore,
+    telegram::{self, InitData},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct MiniAppArgs {
+    /// Bot of the Mini App, the default one if absent.
+    bot: Option<String>,
+    /// Only for `/miniapp_bind`.
+    rid: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LinkStatus {
+    telegram_id: String,
+    linked: bool,
+    needs_relogin: bool,
+    identity: Option<GitHubIdentity>,
+}
+
+fn verify(
+    headers: &HeaderMap,
+    payload: &MiniAppArgs,
+) -> Result<(&'static str, InitData), AppError> {
+    let bot = bot::resolve(payload.bot.as_deref())?;
+    let init_data = headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("tma "))
+        .ok_or_else(|| AppError::Unauthorized("Missing Authorization: tma <initData>".into()))?;
+
+    Ok((bot, telegram::verify_init_data(bot, init_data)?))
+}
+
+pub async fn miniapp_login(
+    headers: HeaderMap,
+    Query(payload): Query<MiniAppArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    let (bot, init_data) = verify(&headers, &payload)?;
+
+    let login_state = LoginState {
+        flow: Flow::Web,
+        telegram_id: Some(init_data.telegram_id),
+        bot: Some(bot.to_string()),
+        code_challenge: None,
+        browser: None,
+    };
+    let url = authorize_url(store()?, &login_state).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((headers, Json(json!({ "url": url.as_str() }))))
+}
+
+pub async fn miniapp_bind(
+    headers: HeaderMap,
+    Query(payload): Query<MiniAppArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    let (bot, init_data) = verify(&headers, &payload)?;
+    let rid = payload
+        .rid
+        .or(init_data.start_param)
+        .ok_or_else(|| AppError::BadRequest("Missing rid".into()))?;
+
+    let store = store()?;
+    bind_pending(store, &rid, Some(bot), &init_data.telegram_id).await?;
+    let status = link_status(store, bot, init_data.telegram_id).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((headers, Json(status)))
+}
+
+pub async fn miniapp_status(
+    headers: HeaderMap,
+    Query(payload): Query<MiniAppArgs>,
+) -> Result<impl IntoResponse, AppError> {
+    let (bot, init_data) = verify(&headers, &payload)?;
+    let status = link_status(store()?, bot, init_data.telegram_id).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    Ok((headers, Json(status)))
+}
+
+async fn link_status(
+    store: &dyn CredentialStore,
+    bot: &str,
+    telegram_id: String,
+) -> Result<LinkStatus, AppError> {
+    let (linked, needs_relogin, identity) =
+        match load_credential(store, &bot::account(bot, &telegram_id)).await {
+            Ok(x) => (true, x.needs_relogin, x.identity),
+            Err(AppError::NotLoggedIn(_)) => (false, false, None),
+            Err(e) => return Err(e),
+        };
+
+    Ok(LinkStatus {
+        telegram_id,
+        linked,
+        needs_relogin,
+        identity,
+    })
+}
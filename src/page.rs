@@ -0,0 +1,278 @@
+//! HTML pages shown in the browser during a web login.
+//!
+//! Every page comes in English and Simplified Chinese, picked by
+//! `Accept-Language`. Any of them can be replaced by a file
+//! `<TEMPLATE_DIR>/<lang>/<page>.html`, `lang` being `en` or `zh-CN`, which
+//! is read at startup. `{{name}}` in a page is replaced by the HTML escaped
+//! value of `name`:
+//!
+//! | page           | shown when                              | variables                         |
+//! |----------------|-----------------------------------------|-----------------------------------|
+//! | `login`        | GitHub sent the user back               | `link` (to the bot), `rid`, `bot` |
+//! | `login_widget` | same, for a bot with a token configured | same, and `auth_url`              |
+//! | `success`      | the account is linked                   |                                   |
+//! | `expired`      | the login link was used or has expired  | `request_id`                      |
+//! | `denied`       | the account is not on the allowlist     | `request_id`                      |
+//! | `error`        | anything else went wrong                | `reason`, `request_id`            |
+//!
+//! `login_widget` offers the Telegram Login Widget next to the link, and
+//! `auth_url` is where the widget should send the user. The widget only
+//! loads once the domain of `REDIRECT_URL` is set for the bot with
+//! `/setdomain` at @BotFather. `rid` is the pending login, e.g. for a link
+//! that opens a Mini App with it, `https://t.me/{{bot}}/<app>?startapp={{rid}}`.
+
+use std::collections::HashMap;
+
+use axum::{
+    http::{header::ACCEPT_LANGUAGE, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use tracing::info;
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lang {
+    En,
+    ZhCn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Page {
+    Login,
+    LoginWidget,
+    Success,
+    Expired,
+    Denied,
+    Error,
+}
+
+const LANGS: [Lang; 2] = [Lang::En, Lang::ZhCn];
+const PAGES: [Page; 6] = [
+    Page::Login,
+    Page::LoginWidget,
+    Page::Success,
+    Page::Expired,
+    Page::Denied,
+    Page::Error,
+];
+
+static TEMPLATES: Lazy<HashMap<(Lang, Page), String>> = Lazy::new(|| {
+    let dir = std::env::var("TEMPLATE_DIR").ok();
+    let mut templates = HashMap::new();
+
+    for lang in LANGS {
+        for page in PAGES {
+            let custom = dir.as_ref().and_then(|dir| {
+                let path = format!("{dir}/{}/{}.html", lang.tag(), page.name());
+                let s = std::fs::read_to_string(&path).ok()?;
+                info!("Using template {path}");
+                Some(s)
+            });
+
+            templates.insert((lang, page), custom.unwrap_or_else(|| builtin(lang, page)));
+        }
+    }
+
+    templates
+});
+
+pub fn init() {
+    Lazy::force(&TEMPLATES);
+}
+
+impl Lang {
+    fn tag(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::ZhCn => "zh-CN",
+        }
+    }
+
+    /// The first language in `Accept-Language` (by `q`) that we have pages for.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(ACCEPT_LANGUAGE).and_then(|x| x.to_str().ok()) else {
+            return Lang::En;
+        };
+
+        let mut langs: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|x| {
+                let mut parts = x.split(';');
+                let tag = parts.next().unwrap_or_default().trim();
+                let q = parts
+                    .find_map(|x| x.trim().strip_prefix("q="))
+                    .and_then(|x| x.parse().ok())
+                    .unwrap_or(1.0);
+                (tag, q)
+            })
+            .collect();
+        // Stable, so equal weights keep the order of the header.
+        langs.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        langs
+            .into_iter()
+            .filter(|(_, q)| *q > 0.0)
+            .find_map(|(tag, _)| {
+                let tag = tag.to_ascii_lowercase();
+                if tag.starts_with("zh") {
+                    Some(Lang::ZhCn)
+                } else if tag.starts_with("en") {
+                    Some(Lang::En)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(Lang::En)
+    }
+}
+
+impl Page {
+    fn name(self) -> &'static str {
+        match self {
+            Page::Login => "login",
+            Page::LoginWidget => "login_widget",
+            Page::Success => "success",
+            Page::Expired => "expired",
+            Page::Denied => "denied",
+            Page::Error => "error",
+        }
+    }
+}
+
+pub fn render(lang: Lang, page: Page, status: StatusCode, vars: &[(&str, &str)]) -> Response {
+    let mut s = TEMPLATES[&(lang, page)].clone();
+    for (name, value) in vars {
+        s = s.replace(&format!("{{{{{name}}}}}"), &escape(value));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+
+    (status, headers, Html(s)).into_response()
+}
+
+/// The page for `err`, for requests coming from a browser.
+pub fn error(lang: Lang, err: &AppError) -> Response {
+    let request_id = err.log();
+    let page = match err {
+        AppError::NotFound(_) | AppError::Gone(_) => Page::Expired,
+        AppError::NotAllowed(_) => Page::Denied,
+        _ => Page::Error,
+    };
+
+    render(
+        lang,
+        page,
+        err.status(),
+        &[("reason", err.message()), ("request_id", &request_id)],
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn builtin(lang: Lang, page: Page) -> String {
+    let (title, body) = match (lang, page) {
+        (Lang::En, Page::Login) => (
+            "Almost there",
+            r#"<p>Your GitHub account is authorized.</p>
+<p><a href="{{link}}">Open Telegram to finish linking it</a>.</p>"#,
+        ),
+        (Lang::ZhCn, Page::Login) => (
+            "还差一步",
+            r#"<p>GitHub 账号已授权。</p>
+<p><a href="{{link}}">打开 Telegram 完成绑定</a>。</p>"#,
+        ),
+        (Lang::En, Page::LoginWidget) => (
+            "Almost there",
+            r#"<p>Your GitHub account is authorized. Log in with Telegram to link it:</p>
+<p><script async src="https://telegram.org/js/telegram-widget.js?22" data-telegram-login="{{bot}}" data-size="large" data-auth-url="{{auth_url}}"></script></p>
+<p>Or <a href="{{link}}">open Telegram to finish linking it</a>.</p>"#,
+        ),
+        (Lang::ZhCn, Page::LoginWidget) => (
+            "还差一步",
+            r#"<p>GitHub 账号已授权。使用 Telegram 登录以完成绑定：</p>
+<p><script async src="https://telegram.org/js/telegram-widget.js?22" data-telegram-login="{{bot}}" data-size="large" data-auth-url="{{auth_url}}" data-lang="zh-hans"></script></p>
+<p>或者<a href="{{link}}">打开 Telegram 完成绑定</a>。</p>"#,
+        ),
+        (Lang::En, Page::Success) => (
+            "Linked",
+            "<p>Your GitHub account is linked. You can go back to Telegram now.</p>",
+        ),
+        (Lang::ZhCn, Page::Success) => (
+            "绑定成功",
+            "<p>GitHub 账号已绑定，可以返回 Telegram 了。</p>",
+        ),
+        (Lang::En, Page::Expired) => (
+            "Link expired",
+            r#"<p>This login link has already been used or has expired.</p>
+<p>Please start again from the bot.</p>
+<p class="id">Request ID: {{request_id}}</p>"#,
+        ),
+        (Lang::ZhCn, Page::Expired) => (
+            "链接已失效",
+            r#"<p>此登录链接已被使用或已过期。</p>
+<p>请在机器人中重新开始登录。</p>
+<p class="id">请求 ID：{{request_id}}</p>"#,
+        ),
+        (Lang::En, Page::Denied) => (
+            "Access denied",
+            r#"<p>Sorry, this GitHub account is not allowed to log in here.</p>
+<p>Only members of the organizations and teams configured for this bot can link their accounts. If you think this is a mistake, please ask the maintainers to add you.</p>
+<p class="id">Request ID: {{request_id}}</p>"#,
+        ),
+        (Lang::ZhCn, Page::Denied) => (
+            "无权登录",
+            r#"<p>抱歉，此 GitHub 账号无权在此登录。</p>
+<p>只有本机器人指定的组织或团队成员可以绑定账号。如果你认为这是误判，请联系维护者将你加入。</p>
+<p class="id">请求 ID：{{request_id}}</p>"#,
+        ),
+        (Lang::En, Page::Error) => (
+            "Login failed",
+            r#"<p>Sorry, the login failed: {{reason}}.</p>
+<p>Please try again later. If it keeps failing, tell the maintainers the request ID below.</p>
+<p class="id">Request ID: {{request_id}}</p>"#,
+        ),
+        (Lang::ZhCn, Page::Error) => (
+            "登录失败",
+            r#"<p>抱歉，登录失败：{{reason}}。</p>
+<p>请稍后重试。如果一直失败，请把下面的请求 ID 告诉维护者。</p>
+<p class="id">请求 ID：{{request_id}}</p>"#,
+        ),
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="{}">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 36em; margin: 4em auto; padding: 0 1em; line-height: 1.6; }}
+.id {{ color: #888; font-size: small; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#,
+        lang.tag()
+    )
+}
@@ -0,0 +1,71 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    credential::StoredCredential,
+    crypto, env_or, error,
+    error::AppError,
+    random_id,
+    store::{CredentialStore, TempKind},
+    store_error,
+};
+
+static PENDING_LOGIN_TTL: Lazy<u64> = Lazy::new(|| env_or("PENDING_LOGIN_TTL", 600));
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PendingLogin {
+    #[serde(flatten)]
+    pub credential: StoredCredential,
+    /// Set when the login was started for a specific telegram user.
+    #[serde(default)]
+    pub telegram_id: Option<String>,
+    /// Bot the login was started from, the default one if `None`.
+    #[serde(default)]
+    pub bot: Option<String>,
+}
+
+/// Park a login until the user opens the telegram link, returning its `rid`.
+pub async fn put(store: &dyn CredentialStore, login: &PendingLogin) -> Result<String, AppError> {
+    let rid = random_id();
+
+    let s = serde_json::to_string(login).map_err(|e| error(&e))?;
+    let s = crypto::seal(&pending_aad(&rid), &s).map_err(|e| error(&e))?;
+    store
+        .put_temp(TempKind::PendingLogin, &rid, &s, *PENDING_LOGIN_TTL)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    Ok(rid)
+}
+
+/// Look at a pending login without claiming it.
+pub async fn get(store: &dyn CredentialStore, rid: &str) -> Result<Option<PendingLogin>, AppError> {
+    let s = store
+        .get_temp(TempKind::PendingLogin, rid)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    s.map(|s| open(rid, &s)).transpose()
+}
+
+/// Claim a pending login. Each `rid` can be claimed once.
+pub async fn take(
+    store: &dyn CredentialStore,
+    rid: &str,
+) -> Result<Option<PendingLogin>, AppError> {
+    let s = store
+        .take_temp(TempKind::PendingLogin, rid)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    s.map(|s| open(rid, &s)).transpose()
+}
+
+fn open(rid: &str, s: &str) -> Result<PendingLogin, AppError> {
+    let s = crypto::open(&pending_aad(rid), s).map_err(|e| error(&e))?;
+    serde_json::from_str(&s).map_err(|e| error(&e))
+}
+
+fn pending_aad(rid: &str) -> String {
+    format!("pending:{rid}")
+}
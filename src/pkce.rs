@@ -0,0 +1,51 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{memcmp, sha::sha256};
+
+/// RFC 7636 verifiers are 43 to 128 unreserved characters. An S256 challenge
+/// is the 43 character base64url encoding of a SHA-256 digest, so it passes
+/// the same check.
+pub fn is_valid(s: &str) -> bool {
+    (43..=128).contains(&s.len())
+        && s.bytes()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, b'-' | b'.' | b'_' | b'~'))
+}
+
+pub fn challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(sha256(verifier.as_bytes()))
+}
+
+pub fn verify(verifier: &str, challenge: &str) -> bool {
+    let expected = self::challenge(verifier);
+
+    expected.len() == challenge.len() && memcmp::eq(expected.as_bytes(), challenge.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 7636, Appendix B.
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn rfc_7636_vector() {
+        assert!(is_valid(VERIFIER));
+        assert!(is_valid(CHALLENGE));
+        assert_eq!(challenge(VERIFIER), CHALLENGE);
+        assert!(verify(VERIFIER, CHALLENGE));
+    }
+
+    #[test]
+    fn rejects_other_verifier() {
+        assert!(!verify(&VERIFIER.replace('d', "e"), CHALLENGE));
+        assert!(!verify(VERIFIER, &CHALLENGE[1..]));
+    }
+
+    #[test]
+    fn rejects_malformed_verifier() {
+        assert!(!is_valid("short"));
+        assert!(!is_valid(&"a".repeat(129)));
+        assert!(!is_valid(&format!("{}+", &VERIFIER[1..])));
+    }
+}
@@ -0,0 +1,222 @@
+use std::{io, sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{info, log::error, warn};
+
+use crate::{
+    allowlist,
+    credential::StoredCredential,
+    credential_aad, crypto, env_or,
+    error::AppError,
+    now,
+    store::{CredentialStore, TempKind},
+};
+
+static INTERVAL: Lazy<u64> = Lazy::new(|| env_or("REFRESH_INTERVAL", 60));
+static AHEAD: Lazy<i64> = Lazy::new(|| env_or("REFRESH_AHEAD", 600));
+static JITTER: Lazy<i64> = Lazy::new(|| env_or("REFRESH_JITTER", 120));
+static CONCURRENCY: Lazy<usize> = Lazy::new(|| env_or("REFRESH_CONCURRENCY", 4));
+static MAX_FAILURES: Lazy<u32> = Lazy::new(|| env_or("REFRESH_MAX_FAILURES", 5));
+/// Seconds a replica has to refresh a credential before another one may try.
+static LEASE: Lazy<u64> = Lazy::new(|| env_or("REFRESH_LEASE", 120));
+
+static FAILURES: Lazy<DashMap<String, Failure>> = Lazy::new(DashMap::new);
+
+struct Failure {
+    count: u32,
+    retry_at: i64,
+}
+
+/// Schedule a refresh of `id` ahead of `expires_at`.
+pub async fn schedule(store: &dyn CredentialStore, id: &str, expires_at: i64) -> io::Result<()> {
+    let jitter = rand::thread_rng().gen_range(0..=*JITTER);
+    let at = expires_at - *AHEAD - jitter;
+
+    store.schedule(id, at).await
+}
+
+pub fn spawn(store: &'static dyn CredentialStore) {
+    tokio::spawn(run(store));
+}
+
+async fn run(store: &'static dyn CredentialStore) {
+    if let Err(e) = adopt_unscheduled(store).await {
+        error!("Failed to schedule existing tokens: {e}");
+    }
+
+    let semaphore = Arc::new(Semaphore::new(*CONCURRENCY));
+    let mut interval = tokio::time::interval(Duration::from_secs(*INTERVAL));
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = tick(store, &semaphore).await {
+            error!("Failed to run token refresh: {e}");
+        }
+    }
+}
+
+/// Pick up credentials stored before the scheduler existed.
+async fn adopt_unscheduled(store: &dyn CredentialStore) -> io::Result<()> {
+    for key in store.list().await? {
+        if store.scheduled_at(&key).await?.is_some() {
+            continue;
+        }
+
+        let Some(s) = store.get(&key).await? else {
+            continue;
+        };
+
+        let Ok(s) = crypto::open(&credential_aad(&key), &s) else {
+            continue;
+        };
+
+        if let Some(expires_at) = StoredCredential::decode(&s)
+            .ok()
+            .and_then(|(x, _)| x.refresh_at())
+        {
+            info!("Scheduling refresh for {key}");
+            schedule(store, &key, expires_at).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn tick(store: &'static dyn CredentialStore, semaphore: &Arc<Semaphore>) -> io::Result<()> {
+    let now = now();
+    let due = store.due(now).await?;
+    let mut tasks = JoinSet::new();
+
+    for id in due {
+        if FAILURES.get(&id).is_some_and(|x| x.retry_at > now) {
+            continue;
+        }
+
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("refresh semaphore is never closed");
+
+        tasks.spawn(async move {
+            refresh_one(store, id).await;
+            drop(permit);
+        });
+    }
+
+    while tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// Replicas share the schedule, so a due id is skipped while another one
+/// holds its lease, see [`claim_lease`].
+async fn refresh_one(store: &dyn CredentialStore, id: String) {
+    let id = id.as_str();
+
+    match store.get_temp(TempKind::RefreshLease, id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return,
+        Err(e) => {
+            error!("Failed to check refresh lease of {id}: {e}");
+            return;
+        }
+    }
+
+    match store.get(id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            FAILURES.remove(id);
+            if let Err(e) = store.unschedule(id).await {
+                error!("Failed to unschedule {id}: {e}");
+            }
+            return;
+        }
+        Err(e) => {
+            error!("Failed to check token of {id}: {e}");
+            return;
+        }
+    }
+
+    // Skips tokens that `get_token` has refreshed since this tick read the schedule.
+    let min_ttl = Some(*AHEAD + *JITTER);
+    match crate::refresh_user_token(store, id, min_ttl).await {
+        Ok(credential) => {
+            info!("Refreshed token of {id}");
+            FAILURES.remove(id);
+            revalidate(store, id, &credential).await;
+            return;
+        }
+        Err(e @ (AppError::ReloginRequired(_) | AppError::NotRefreshable(_))) => {
+            info!("Not refreshing token of {id} anymore: {e}");
+            FAILURES.remove(id);
+            if let Err(e) = store.unschedule(id).await {
+                error!("Failed to unschedule {id}: {e}");
+            }
+            return;
+        }
+        Err(e) => error!("Failed to refresh token of {id}: {e}"),
+    }
+
+    let mut failure = FAILURES.entry(id.to_string()).or_insert(Failure {
+        count: 0,
+        retry_at: 0,
+    });
+    failure.count += 1;
+
+    if failure.count >= *MAX_FAILURES {
+        warn!(
+            "Giving up refreshing token of {id} after {} failures",
+            failure.count
+        );
+        drop(failure);
+        FAILURES.remove(id);
+        if let Err(e) = store.unschedule(id).await {
+            error!("Failed to unschedule {id}: {e}");
+        }
+        return;
+    }
+
+    let backoff = (*INTERVAL as i64) << failure.count.min(6);
+    failure.retry_at = now() + backoff;
+    warn!(
+        "Failed to refresh token of {id} ({} times), retrying in {backoff}s",
+        failure.count
+    );
+}
+
+/// Take the refresh lease of `id`, waiting while another replica holds it.
+/// A refresh token can only be spent once, so only the holder may. The lease
+/// expires by itself should its holder die.
+pub async fn claim_lease(store: &dyn CredentialStore, id: &str) -> io::Result<()> {
+    while !store.claim_temp(TempKind::RefreshLease, id, *LEASE).await? {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    Ok(())
+}
+
+pub async fn release_lease(store: &dyn CredentialStore, id: &str) {
+    if let Err(e) = store.delete_temp(TempKind::RefreshLease, id).await {
+        warn!("Failed to release refresh lease of {id}: {e}");
+    }
+}
+
+/// Users who have left the allowed orgs and teams lose access with the next refresh.
+async fn revalidate(store: &dyn CredentialStore, id: &str, credential: &StoredCredential) {
+    let res = allowlist::check(&credential.token.access_token, credential.identity.as_ref()).await;
+
+    match res {
+        Ok(()) => {}
+        Err(AppError::NotAllowed(e)) => {
+            info!("Logging out {id}: {e}");
+            if let Err(e) = crate::logout::logout_user(store, id).await {
+                error!("Failed to log out {id}: {e}");
+            }
+        }
+        Err(e) => warn!("Failed to check whether {id} is still allowed: {e}"),
+    }
+}
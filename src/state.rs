@@ -0,0 +1,131 @@
+use axum::http::{header::COOKIE, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use once_cell::sync::Lazy;
+use openssl::{memcmp, sha::sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    env_or, error,
+    error::AppError,
+    random_id,
+    store::{CredentialStore, TempKind},
+    store_error,
+};
+
+static OAUTH_STATE_TTL: Lazy<u64> = Lazy::new(|| env_or("OAUTH_STATE_TTL", 600));
+
+/// Ties a web login to the browser that started it, so nobody can hand the
+/// GitHub callback of their own account to somebody else (login CSRF). Only
+/// the latest login started in a browser can finish.
+const BROWSER_COOKIE: &str = "minzhengbu_login";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Flow {
+    #[default]
+    Web,
+    Cli,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LoginState {
+    pub flow: Flow,
+    pub telegram_id: Option<String>,
+    /// Bot to send the user back to, the default one if `None`.
+    #[serde(default)]
+    pub bot: Option<String>,
+    /// PKCE S256 challenge, required for CLI logins.
+    #[serde(default)]
+    pub code_challenge: Option<String>,
+    /// Hash of the nonce in the browser cookie of a web login. `None` for
+    /// logins started from a Mini App, which are tied to a verified
+    /// `telegram_id` instead.
+    #[serde(default)]
+    pub browser: Option<String>,
+}
+
+/// A fresh browser nonce: its hash for [`LoginState::browser`], and the
+/// `Set-Cookie` value that hands it to the browser.
+pub fn bind_browser(secure: bool) -> (String, String) {
+    let nonce = random_id();
+    let cookie = format!(
+        "{BROWSER_COOKIE}={nonce}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{}",
+        *OAUTH_STATE_TTL,
+        if secure { "; Secure" } else { "" }
+    );
+
+    (browser_hash(&nonce), cookie)
+}
+
+/// Whether the callback of `state` comes from the browser that started it.
+pub fn check_browser(state: &LoginState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected) = &state.browser else {
+        return Ok(());
+    };
+
+    let nonce = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .find_map(|x| x.trim().strip_prefix(BROWSER_COOKIE)?.strip_prefix('='));
+
+    match nonce.map(browser_hash) {
+        Some(x) if x.len() == expected.len() && memcmp::eq(x.as_bytes(), expected.as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(AppError::Forbidden(
+            "Login was not started in this browser".into(),
+        )),
+    }
+}
+
+fn browser_hash(nonce: &str) -> String {
+    URL_SAFE_NO_PAD.encode(sha256(nonce.as_bytes()))
+}
+
+pub async fn mint(store: &dyn CredentialStore, state: &LoginState) -> Result<String, AppError> {
+    let id = random_id();
+
+    let s = serde_json::to_string(state).map_err(|e| error(&e))?;
+    store
+        .put_temp(TempKind::OAuthState, &id, &s, *OAUTH_STATE_TTL)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    Ok(id)
+}
+
+/// Claim `state` for a callback of `flow`. Each state can be claimed once, so
+/// missing, reused and expired states all end up here as not found.
+pub async fn consume(
+    store: &dyn CredentialStore,
+    state: Option<&str>,
+    flow: Flow,
+) -> Result<LoginState, AppError> {
+    let Some(state) = state else {
+        return Err(AppError::BadRequest("Missing state".into()));
+    };
+
+    let s = store
+        .take_temp(TempKind::OAuthState, state)
+        .await
+        .map_err(|e| store_error(&e))?;
+
+    let Some(s) = s else {
+        return Err(AppError::NotFound(format!(
+            "Unknown or expired state {state}"
+        )));
+    };
+
+    let res: LoginState = serde_json::from_str(&s).map_err(|e| error(&e))?;
+
+    if res.flow != flow {
+        return Err(AppError::Forbidden(format!(
+            "State {state} was issued for {:?} login",
+            res.flow
+        )));
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,781 @@
+//! Persistence behind minzhengbu.
+//!
+//! Values are opaque strings to the store: sealing and serialization happen
+//! in the callers, so every backend stores exactly the same bytes.
+//!
+//! `STORAGE` picks the backend: `redis` (default), `sqlite` (the file at
+//! `SQLITE_PATH`) or `memory`.
+
+use std::{
+    collections::HashSet,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use redis::{aio::MultiplexedConnection, AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{keys, now};
+
+/// Short-lived records, each kind in its own namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TempKind {
+    PendingLogin,
+    OAuthState,
+    DeviceLogin,
+    /// Nonces of signed service requests, so each can only be used once.
+    Nonce,
+    /// Held while refreshing a credential, so only one replica does.
+    RefreshLease,
+}
+
+impl TempKind {
+    fn name(self) -> &'static str {
+        match self {
+            TempKind::PendingLogin => "pending",
+            TempKind::OAuthState => "state",
+            TempKind::DeviceLogin => "device",
+            TempKind::Nonce => "nonce",
+            TempKind::RefreshLease => "lease",
+        }
+    }
+}
+
+#[async_trait]
+pub trait CredentialStore: Send + Sync {
+    /// Credential of a telegram user.
+    async fn get(&self, id: &str) -> io::Result<Option<String>>;
+    async fn put(&self, id: &str, value: &str) -> io::Result<()>;
+    /// Returns whether there was anything to delete.
+    async fn delete(&self, id: &str) -> io::Result<bool>;
+    /// Telegram ids of all stored credentials.
+    async fn list(&self) -> io::Result<Vec<String>>;
+    /// Set the credential of `id` to `new` only if it is currently `expected`
+    /// (`None` meaning absent). Returns whether it was set.
+    async fn compare_and_swap(
+        &self,
+        id: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> io::Result<bool>;
+
+    async fn put_temp(&self, kind: TempKind, key: &str, value: &str, ttl: u64) -> io::Result<()>;
+    /// Like `put_temp`, but only if there is no live record yet. Returns whether it was set.
+    async fn claim_temp(&self, kind: TempKind, key: &str, ttl: u64) -> io::Result<bool>;
+    async fn get_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>>;
+    /// Get and delete in one step, so a record can only be claimed once.
+    async fn take_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>>;
+    async fn delete_temp(&self, kind: TempKind, key: &str) -> io::Result<()>;
+
+    /// Add `id` to the telegram ids linked to the GitHub account `github`.
+    async fn add_link(&self, github: &str, id: &str) -> io::Result<()>;
+    async fn remove_link(&self, github: &str, id: &str) -> io::Result<()>;
+    async fn links(&self, github: &str) -> io::Result<Vec<String>>;
+
+    /// Schedule a refresh of the credential of `id` at unix time `at`.
+    async fn schedule(&self, id: &str, at: i64) -> io::Result<()>;
+    async fn unschedule(&self, id: &str) -> io::Result<()>;
+    async fn scheduled_at(&self, id: &str) -> io::Result<Option<i64>>;
+    /// Ids whose refresh is due at or before `until`.
+    async fn due(&self, until: i64) -> io::Result<Vec<String>>;
+}
+
+pub struct RedisStore {
+    conn: MultiplexedConnection,
+}
+
+impl RedisStore {
+    pub fn new(conn: MultiplexedConnection) -> Self {
+        Self { conn }
+    }
+
+    fn temp_key(kind: TempKind, key: &str) -> String {
+        match kind {
+            TempKind::PendingLogin => keys::pending(key),
+            TempKind::OAuthState => keys::oauth_state(key),
+            TempKind::DeviceLogin => keys::device(key),
+            TempKind::Nonce => keys::nonce(key),
+            TempKind::RefreshLease => keys::refresh_lease(key),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialStore for RedisStore {
+    async fn get(&self, id: &str) -> io::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        conn.get(keys::credential(id))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn put(&self, id: &str, value: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.set(keys::credential(id), value)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<bool> {
+        let mut conn = self.conn.clone();
+        conn.del(keys::credential(id))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let mut iter = conn
+            .scan_match::<_, String>(keys::credential_pattern())
+            .await
+            .map_err(io::Error::other)?;
+        let mut ids = vec![];
+
+        while let Some(key) = iter.next_item().await {
+            if let Some(id) = keys::credential_id(&key) {
+                ids.push(id.to_string());
+            }
+        }
+
+        Ok(ids)
+    }
+
+    async fn compare_and_swap(
+        &self,
+        id: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> io::Result<bool> {
+        let script = redis::Script::new(
+            r"local current = redis.call('GET', KEYS[1])
+            if (ARGV[1] == '0' and current == false) or (ARGV[1] == '1' and current == ARGV[2]) then
+                redis.call('SET', KEYS[1], ARGV[3])
+                return 1
+            end
+            return 0",
+        );
+
+        let mut conn = self.conn.clone();
+        script
+            .key(keys::credential(id))
+            .arg(if expected.is_some() { "1" } else { "0" })
+            .arg(expected.unwrap_or_default())
+            .arg(new)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn put_temp(&self, kind: TempKind, key: &str, value: &str, ttl: u64) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.set_ex(Self::temp_key(kind, key), value, ttl)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn claim_temp(&self, kind: TempKind, key: &str, ttl: u64) -> io::Result<bool> {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(ttl as usize));
+
+        let mut conn = self.conn.clone();
+        let res: Option<String> = conn
+            .set_options(Self::temp_key(kind, key), "1", options)
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(res.is_some())
+    }
+
+    async fn get_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        conn.get(Self::temp_key(kind, key))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn take_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        conn.get_del(Self::temp_key(kind, key))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn delete_temp(&self, kind: TempKind, key: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.del(Self::temp_key(kind, key))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn add_link(&self, github: &str, id: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.sadd(keys::github_link(github), id)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn remove_link(&self, github: &str, id: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.srem(keys::github_link(github), id)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn links(&self, github: &str) -> io::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        conn.smembers(keys::github_link(github))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn schedule(&self, id: &str, at: i64) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.zadd(keys::refresh_schedule(), id, at)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn unschedule(&self, id: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.zrem(keys::refresh_schedule(), id)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn scheduled_at(&self, id: &str) -> io::Result<Option<i64>> {
+        let mut conn = self.conn.clone();
+        conn.zscore(keys::refresh_schedule(), id)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn due(&self, until: i64) -> io::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        conn.zrangebyscore(keys::refresh_schedule(), "-inf", until)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+/// Keeps everything in process memory, for tests and throwaway instances.
+#[derive(Default)]
+pub struct MemoryStore {
+    credentials: DashMap<String, String>,
+    temp: DashMap<(TempKind, String), (String, i64)>,
+    links: DashMap<String, HashSet<String>>,
+    schedule: DashMap<String, i64>,
+}
+
+impl MemoryStore {
+    fn live_temp(&self, kind: TempKind, key: &str) -> Option<String> {
+        let key = (kind, key.to_string());
+        self.temp
+            .remove_if(&key, |_, (_, expires_at)| *expires_at <= now());
+        self.temp.get(&key).map(|x| x.0.clone())
+    }
+}
+
+#[async_trait]
+impl CredentialStore for MemoryStore {
+    async fn get(&self, id: &str) -> io::Result<Option<String>> {
+        Ok(self.credentials.get(id).map(|x| x.clone()))
+    }
+
+    async fn put(&self, id: &str, value: &str) -> io::Result<()> {
+        self.credentials.insert(id.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<bool> {
+        Ok(self.credentials.remove(id).is_some())
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.credentials.iter().map(|x| x.key().clone()).collect())
+    }
+
+    async fn compare_and_swap(
+        &self,
+        id: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> io::Result<bool> {
+        let mut entry = self.credentials.entry(id.to_string());
+        let current = match &mut entry {
+            dashmap::mapref::entry::Entry::Occupied(x) => Some(x.get().as_str()),
+            dashmap::mapref::entry::Entry::Vacant(_) => None,
+        };
+
+        if current != expected {
+            return Ok(false);
+        }
+
+        entry.insert(new.to_string());
+
+        Ok(true)
+    }
+
+    async fn put_temp(&self, kind: TempKind, key: &str, value: &str, ttl: u64) -> io::Result<()> {
+        self.temp.insert(
+            (kind, key.to_string()),
+            (value.to_string(), now() + ttl as i64),
+        );
+        Ok(())
+    }
+
+    async fn claim_temp(&self, kind: TempKind, key: &str, ttl: u64) -> io::Result<bool> {
+        let now = now();
+        let mut claimed = false;
+
+        self.temp
+            .entry((kind, key.to_string()))
+            .and_modify(|(_, expires_at)| {
+                if *expires_at <= now {
+                    *expires_at = now + ttl as i64;
+                    claimed = true;
+                }
+            })
+            .or_insert_with(|| {
+                claimed = true;
+                (String::new(), now + ttl as i64)
+            });
+
+        Ok(claimed)
+    }
+
+    async fn get_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        Ok(self.live_temp(kind, key))
+    }
+
+    async fn take_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .temp
+            .remove(&(kind, key.to_string()))
+            .filter(|(_, (_, expires_at))| *expires_at > now())
+            .map(|(_, (value, _))| value))
+    }
+
+    async fn delete_temp(&self, kind: TempKind, key: &str) -> io::Result<()> {
+        self.temp.remove(&(kind, key.to_string()));
+        Ok(())
+    }
+
+    async fn add_link(&self, github: &str, id: &str) -> io::Result<()> {
+        self.links
+            .entry(github.to_string())
+            .or_default()
+            .insert(id.to_string());
+        Ok(())
+    }
+
+    async fn remove_link(&self, github: &str, id: &str) -> io::Result<()> {
+        if let Some(mut ids) = self.links.get_mut(github) {
+            ids.remove(id);
+        }
+        self.links.remove_if(github, |_, x| x.is_empty());
+        Ok(())
+    }
+
+    async fn links(&self, github: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .links
+            .get(github)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn schedule(&self, id: &str, at: i64) -> io::Result<()> {
+        self.schedule.insert(id.to_string(), at);
+        Ok(())
+    }
+
+    async fn unschedule(&self, id: &str) -> io::Result<()> {
+        self.schedule.remove(id);
+        Ok(())
+    }
+
+    async fn scheduled_at(&self, id: &str) -> io::Result<Option<i64>> {
+        Ok(self.schedule.get(id).map(|x| *x))
+    }
+
+    async fn due(&self, until: i64) -> io::Result<Vec<String>> {
+        Ok(self
+            .schedule
+            .iter()
+            .filter(|x| *x.value() <= until)
+            .map(|x| x.key().clone())
+            .collect())
+    }
+}
+
+/// A single SQLite file, for small deployments without Redis. Replicas can
+/// share it only on the same host.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(io::Error::other)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA busy_timeout = 5000;
+            CREATE TABLE IF NOT EXISTS credentials (
+                id TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS temp (
+                kind TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                expires_at INTEGER NOT NULL,
+                PRIMARY KEY (kind, key)
+            );
+            CREATE INDEX IF NOT EXISTS temp_expires_at ON temp (expires_at);
+            CREATE TABLE IF NOT EXISTS links (
+                github TEXT NOT NULL,
+                id TEXT NOT NULL,
+                PRIMARY KEY (github, id)
+            );
+            CREATE TABLE IF NOT EXISTS schedule (
+                id TEXT PRIMARY KEY,
+                at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS schedule_at ON schedule (at);",
+        )
+        .map_err(io::Error::other)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` on the connection off the async runtime, since SQLite blocks.
+    async fn with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&conn)
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)
+    }
+}
+
+#[async_trait]
+impl CredentialStore for SqliteStore {
+    async fn get(&self, id: &str) -> io::Result<Option<String>> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.query_row(
+                "SELECT value FROM credentials WHERE id = ?1",
+                params![id],
+                |x| x.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn put(&self, id: &str, value: &str) -> io::Result<()> {
+        let (id, value) = (id.to_string(), value.to_string());
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO credentials (id, value) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET value = excluded.value",
+                params![id, value],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<bool> {
+        let id = id.to_string();
+        self.with(move |conn| conn.execute("DELETE FROM credentials WHERE id = ?1", params![id]))
+            .await
+            .map(|x| x > 0)
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        self.with(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM credentials")?;
+            let ids = stmt.query_map([], |x| x.get(0))?.collect();
+            ids
+        })
+        .await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        id: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> io::Result<bool> {
+        let (id, expected, new) = (
+            id.to_string(),
+            expected.map(|x| x.to_string()),
+            new.to_string(),
+        );
+
+        // Each a single statement, so atomic on their own.
+        self.with(move |conn| match expected {
+            Some(expected) => conn.execute(
+                "UPDATE credentials SET value = ?3 WHERE id = ?1 AND value = ?2",
+                params![id, expected, new],
+            ),
+            None => conn.execute(
+                "INSERT OR IGNORE INTO credentials (id, value) VALUES (?1, ?2)",
+                params![id, new],
+            ),
+        })
+        .await
+        .map(|x| x == 1)
+    }
+
+    async fn put_temp(&self, kind: TempKind, key: &str, value: &str, ttl: u64) -> io::Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        let now = now();
+
+        self.with(move |conn| {
+            // Nothing expires on its own, so sweep up whenever adding.
+            conn.execute("DELETE FROM temp WHERE expires_at <= ?1", params![now])?;
+            conn.execute(
+                "INSERT OR REPLACE INTO temp (kind, key, value, expires_at) VALUES (?1, ?2, ?3, ?4)",
+                params![kind.name(), key, value, now + ttl as i64],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn claim_temp(&self, kind: TempKind, key: &str, ttl: u64) -> io::Result<bool> {
+        let key = key.to_string();
+        let now = now();
+
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO temp (kind, key, value, expires_at) VALUES (?1, ?2, '1', ?3)
+                ON CONFLICT (kind, key) DO UPDATE
+                SET value = excluded.value, expires_at = excluded.expires_at
+                WHERE temp.expires_at <= ?4",
+                params![kind.name(), key, now + ttl as i64, now],
+            )
+        })
+        .await
+        .map(|x| x == 1)
+    }
+
+    async fn get_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        let key = key.to_string();
+        let now = now();
+
+        self.with(move |conn| {
+            conn.query_row(
+                "SELECT value FROM temp WHERE kind = ?1 AND key = ?2 AND expires_at > ?3",
+                params![kind.name(), key, now],
+                |x| x.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn take_temp(&self, kind: TempKind, key: &str) -> io::Result<Option<String>> {
+        let key = key.to_string();
+        let now = now();
+
+        let res: Option<(String, i64)> = self
+            .with(move |conn| {
+                conn.query_row(
+                    "DELETE FROM temp WHERE kind = ?1 AND key = ?2 RETURNING value, expires_at",
+                    params![kind.name(), key],
+                    |x| Ok((x.get(0)?, x.get(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(res
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(value, _)| value))
+    }
+
+    async fn delete_temp(&self, kind: TempKind, key: &str) -> io::Result<()> {
+        let key = key.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM temp WHERE kind = ?1 AND key = ?2",
+                params![kind.name(), key],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn add_link(&self, github: &str, id: &str) -> io::Result<()> {
+        let (github, id) = (github.to_string(), id.to_string());
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO links (github, id) VALUES (?1, ?2)",
+                params![github, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_link(&self, github: &str, id: &str) -> io::Result<()> {
+        let (github, id) = (github.to_string(), id.to_string());
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM links WHERE github = ?1 AND id = ?2",
+                params![github, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn links(&self, github: &str) -> io::Result<Vec<String>> {
+        let github = github.to_string();
+        self.with(move |conn| {
+            let mut stmt = conn.prepare("SELECT id FROM links WHERE github = ?1")?;
+            let ids = stmt.query_map(params![github], |x| x.get(0))?.collect();
+            ids
+        })
+        .await
+    }
+
+    async fn schedule(&self, id: &str, at: i64) -> io::Result<()> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO schedule (id, at) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET at = excluded.at",
+                params![id, at],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn unschedule(&self, id: &str) -> io::Result<()> {
+        let id = id.to_string();
+        self.with(move |conn| conn.execute("DELETE FROM schedule WHERE id = ?1", params![id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn scheduled_at(&self, id: &str) -> io::Result<Option<i64>> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.query_row("SELECT at FROM schedule WHERE id = ?1", params![id], |x| {
+                x.get(0)
+            })
+            .optional()
+        })
+        .await
+    }
+
+    async fn due(&self, until: i64) -> io::Result<Vec<String>> {
+        self.with(move |conn| {
+            let mut stmt = conn.prepare("SELECT id FROM schedule WHERE at <= ?1")?;
+            let ids = stmt.query_map(params![until], |x| x.get(0))?.collect();
+            ids
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What every backend must do the same.
+    async fn exercise(store: &dyn CredentialStore) {
+        assert_eq!(store.get("1").await.unwrap(), None);
+        store.put("1", "a").await.unwrap();
+        store.put("1", "b").await.unwrap();
+        assert_eq!(store.get("1").await.unwrap().as_deref(), Some("b"));
+        assert_eq!(store.list().await.unwrap(), ["1"]);
+
+        assert!(!store.compare_and_swap("1", Some("a"), "c").await.unwrap());
+        assert!(store.compare_and_swap("1", Some("b"), "c").await.unwrap());
+        assert!(!store.compare_and_swap("1", None, "d").await.unwrap());
+        assert!(store.compare_and_swap("2", None, "d").await.unwrap());
+        assert_eq!(store.get("1").await.unwrap().as_deref(), Some("c"));
+        assert_eq!(store.get("2").await.unwrap().as_deref(), Some("d"));
+
+        assert!(store.delete("1").await.unwrap());
+        assert!(!store.delete("1").await.unwrap());
+        assert_eq!(store.get("1").await.unwrap(), None);
+
+        let kind = TempKind::PendingLogin;
+        store.put_temp(kind, "k", "v", 60).await.unwrap();
+        assert_eq!(
+            store.get_temp(kind, "k").await.unwrap().as_deref(),
+            Some("v")
+        );
+        assert_eq!(
+            store.get_temp(TempKind::OAuthState, "k").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            store.take_temp(kind, "k").await.unwrap().as_deref(),
+            Some("v")
+        );
+        assert_eq!(store.take_temp(kind, "k").await.unwrap(), None);
+
+        store.put_temp(kind, "gone", "v", 0).await.unwrap();
+        assert_eq!(store.get_temp(kind, "gone").await.unwrap(), None);
+        assert_eq!(store.take_temp(kind, "gone").await.unwrap(), None);
+
+        let kind = TempKind::RefreshLease;
+        assert!(store.claim_temp(kind, "k", 60).await.unwrap());
+        assert!(!store.claim_temp(kind, "k", 60).await.unwrap());
+        store.delete_temp(kind, "k").await.unwrap();
+        assert!(store.claim_temp(kind, "k", 60).await.unwrap());
+        store.put_temp(kind, "stale", "1", 0).await.unwrap();
+        assert!(store.claim_temp(kind, "stale", 60).await.unwrap());
+
+        store.add_link("id:1", "a").await.unwrap();
+        store.add_link("id:1", "a").await.unwrap();
+        store.add_link("id:1", "b").await.unwrap();
+        let mut links = store.links("id:1").await.unwrap();
+        links.sort();
+        assert_eq!(links, ["a", "b"]);
+        store.remove_link("id:1", "a").await.unwrap();
+        assert_eq!(store.links("id:1").await.unwrap(), ["b"]);
+        assert!(store.links("id:2").await.unwrap().is_empty());
+
+        store.schedule("a", 10).await.unwrap();
+        store.schedule("b", 30).await.unwrap();
+        store.schedule("b", 20).await.unwrap();
+        assert_eq!(store.scheduled_at("b").await.unwrap(), Some(20));
+        let mut due = store.due(20).await.unwrap();
+        due.sort();
+        assert_eq!(due, ["a", "b"]);
+        store.unschedule("a").await.unwrap();
+        assert_eq!(store.scheduled_at("a").await.unwrap(), None);
+        assert_eq!(store.due(15).await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        exercise(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store() {
+        exercise(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+}
@@ -0,0 +1,225 @@
+//! Checking data that Telegram signed with the token of one of our bots, which
+//! proves who the user is without trusting whoever passes it along.
+//!
+//! Data older than `TELEGRAM_AUTH_MAX_AGE` seconds (an hour by default) is
+//! refused, so a leaked copy stops working soon.
+
+use std::collections::BTreeMap;
+
+use once_cell::sync::Lazy;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sha::sha256, sign::Signer};
+use serde::Deserialize;
+
+use crate::{bot, env_or, error, error::AppError, now};
+
+static TELEGRAM_AUTH_MAX_AGE: Lazy<i64> = Lazy::new(|| env_or("TELEGRAM_AUTH_MAX_AGE", 3600));
+
+/// What a Mini App learns about the user who opened it.
+#[derive(Debug)]
+pub struct InitData {
+    pub telegram_id: String,
+    /// `startapp` of the link that opened the Mini App.
+    pub start_param: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WebAppUser {
+    id: i64,
+}
+
+/// Fields sent by the Login Widget, see
+/// https://core.telegram.org/widgets/login#checking-authorization
+pub fn verify_widget(
+    bot: &str,
+    fields: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, AppError> {
+    verify(&widget_secret(token(bot)?), fields, now())
+}
+
+/// `Telegram.WebApp.initData` of a Mini App, see
+/// https://core.telegram.org/bots/webapps#validating-data-received-via-the-mini-app
+pub fn verify_init_data(bot: &str, init_data: &str) -> Result<InitData, AppError> {
+    let secret = hmac(b"WebAppData", token(bot)?.as_bytes()).map_err(|e| error(&e))?;
+
+    parse_init_data(&secret, init_data, now())
+}
+
+fn parse_init_data(secret: &[u8], init_data: &str, now: i64) -> Result<InitData, AppError> {
+    let fields: BTreeMap<String, String> = serde_urlencoded::from_str(init_data)
+        .map_err(|e| AppError::BadRequest(format!("Malformed initData: {e}")))?;
+
+    let mut fields = verify(secret, fields, now)?;
+    let user: WebAppUser = fields
+        .get("user")
+        .and_then(|x| serde_json::from_str(x).ok())
+        .ok_or_else(|| AppError::BadRequest("initData has no user".into()))?;
+
+    Ok(InitData {
+        telegram_id: user.id.to_string(),
+        start_param: fields.remove("start_param"),
+    })
+}
+
+fn widget_secret(token: &str) -> [u8; 32] {
+    sha256(token.as_bytes())
+}
+
+fn token(bot: &str) -> Result<&'static str, AppError> {
+    bot::token(bot)
+        .ok_or_else(|| AppError::BadRequest(format!("Bot {bot} has no token configured")))
+}
+
+/// Check `hash` over the other fields, sorted and joined as `key=value` lines,
+/// and `auth_date` against `now`. Returns the fields without `hash`.
+fn verify(
+    secret: &[u8],
+    mut fields: BTreeMap<String, String>,
+    now: i64,
+) -> Result<BTreeMap<String, String>, AppError> {
+    let hash = fields
+        .remove("hash")
+        .ok_or_else(|| AppError::Unauthorized("Missing hash".into()))?;
+
+    let data = fields
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let expected = hex(&hmac(secret, data.as_bytes()).map_err(|e| error(&e))?);
+
+    if expected.len() != hash.len() || !memcmp::eq(expected.as_bytes(), hash.as_bytes()) {
+        return Err(AppError::Unauthorized(
+            "Telegram signature does not match".into(),
+        ));
+    }
+
+    let auth_date: i64 = fields
+        .get("auth_date")
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| AppError::Unauthorized("Missing auth_date".into()))?;
+
+    if now - auth_date > *TELEGRAM_AUTH_MAX_AGE {
+        return Err(AppError::Unauthorized("Telegram data is too old".into()));
+    }
+
+    Ok(fields)
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(message)?;
+    signer.sign_to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "123456:TEST-TOKEN";
+    const AUTH_DATE: i64 = 1700000000;
+
+    fn widget() -> BTreeMap<String, String> {
+        [
+            ("id", "42"),
+            ("first_name", "Ada"),
+            ("username", "ada"),
+            ("auth_date", "1700000000"),
+            (
+                "hash",
+                "33d4644810ee3511130db1141d46736c641990f736d58cfb3e1693a0778b1f41",
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn widget_known_answer() {
+        let fields = verify(&widget_secret(TOKEN), widget(), AUTH_DATE + 60).unwrap();
+
+        assert_eq!(fields.get("id").map(|x| x.as_str()), Some("42"));
+        assert!(!fields.contains_key("hash"));
+    }
+
+    #[test]
+    fn widget_tampered() {
+        let mut fields = widget();
+        fields.insert("id".into(), "43".into());
+        let res = verify(&widget_secret(TOKEN), fields, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+
+        let mut fields = widget();
+        fields.insert("last_name".into(), "Lovelace".into());
+        let res = verify(&widget_secret(TOKEN), fields, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+
+        let res = verify(&widget_secret("654321:OTHER"), widget(), AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn widget_stale() {
+        let now = AUTH_DATE + *TELEGRAM_AUTH_MAX_AGE;
+        assert!(verify(&widget_secret(TOKEN), widget(), now).is_ok());
+
+        let res = verify(&widget_secret(TOKEN), widget(), now + 1);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+
+    const INIT_DATA: &str = "query_id=AAHdF6IQAAAAAN0XohDhrOrc&user=%7B%22id%22%3A42%2C%22first_name%22%3A%22Ada%22%2C%22username%22%3A%22ada%22%7D&auth_date=1700000000&start_param=rid123&hash=e4cd9fd88d0ac770416e27ac4494c970456ff6b9121ad9b6c6e4b936126835e4";
+
+    fn init_data_secret() -> Vec<u8> {
+        hmac(b"WebAppData", TOKEN.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn init_data_known_answer() {
+        let res = parse_init_data(&init_data_secret(), INIT_DATA, AUTH_DATE + 60).unwrap();
+
+        assert_eq!(res.telegram_id, "42");
+        assert_eq!(res.start_param.as_deref(), Some("rid123"));
+    }
+
+    #[test]
+    fn init_data_tampered() {
+        let tampered = INIT_DATA.replace("%3A42", "%3A43");
+        let res = parse_init_data(&init_data_secret(), &tampered, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+
+        let tampered = INIT_DATA.replace("rid123", "rid124");
+        let res = parse_init_data(&init_data_secret(), &tampered, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+
+        // The widget secret of the same bot is a different key.
+        let res = parse_init_data(&widget_secret(TOKEN), INIT_DATA, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn init_data_stale() {
+        let now = AUTH_DATE + *TELEGRAM_AUTH_MAX_AGE + 1;
+        let res = parse_init_data(&init_data_secret(), INIT_DATA, now);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn init_data_missing_hash() {
+        let (rest, _) = INIT_DATA.split_once("&hash=").unwrap();
+        let res = parse_init_data(&init_data_secret(), rest, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn widget_missing_hash() {
+        let mut fields = widget();
+        fields.remove("hash");
+        let res = verify(&widget_secret(TOKEN), fields, AUTH_DATE);
+        assert!(matches!(res, Err(AppError::Unauthorized(_))));
+    }
+}